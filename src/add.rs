@@ -1,34 +1,43 @@
-use async_recursion::async_recursion;
-use flate2::read::GzDecoder;
-use reqwest;
 use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::error::Error;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tar::Archive;
-use tar::EntryType;
 use thiserror::Error;
 use reqwest::Error as ReqwestError;
-use std::io::Cursor;
-use tokio::task;
+use crate::integrity::{self, IntegrityError};
+use crate::metadata_cache::CachedPackument;
+use crate::registry::{Registry, Revalidation};
+use crate::store::{PackageIndex, Store};
+use crate::layout;
+use crate::lifecycle;
+use crate::config::{Config, NetworkMode};
+use crate::link::remove_link;
+use crate::lockfile::{
+    package_key, DependencySpecs, Importer, ImporterSpecifiers, LockedPackage, Lockfile, LockfileError, ROOT_IMPORTER,
+};
+use crate::semver::{Range, SemverError, Version};
+use crate::workspace::{self, Project, Workspace};
 
 #[derive(Debug, Error)]
 pub enum AddCommandError {
     #[error("Failed to parse JSON: {0}")]
-    FailedToParsePackageMeta(reqwest::Error),
+    FailedToParsePackageMeta(serde_json::Error),
     #[error("Failed to retrieve package data: {0}")]
     FailedToRetrievePackageData(reqwest::Error),
     #[error("No valid tarball url for package '{0}'")]
     NoValidTarballUrl(String),
-    #[error("Failed to extract file name from URL")]
-    FailedToExtractFileName,
-    #[error("Failed to spawn aria2c process: {0}")]
-    FailedToSpawnAria2c(std::io::Error),
-    #[error("Failed to wait for aria2c process: {0}")]
-    FailedToWaitForAria2c(std::io::Error),
+    #[error("No version of '{0}' satisfies '{1}'")]
+    NoMatchingVersion(String, String),
+    #[error("{0}")]
+    InvalidVersionSpec(#[from] SemverError),
     #[error("Failed to open file: {0}")]
     FailedToOpenFile(std::io::Error),
+    #[error("'{0}' isn't in the metadata cache, run without --offline to fetch it")]
+    NotInOfflineCache(String),
+    #[error("Missing from the offline cache:\n  {}", .0.join("\n  "))]
+    MissingFromOfflineCache(Vec<String>),
 }
 
 #[derive(Debug, Error)]
@@ -37,8 +46,10 @@ pub enum DownloadError {
     DownloadFailed(ReqwestError),
     #[error("Failed to extract file: {0}")]
     ExtractionFailed(std::io::Error),
-    #[error("Failed to create directories: {0}")]
-    DirectoryCreationFailed(std::io::Error),
+    #[error("Integrity check failed for {0}: {1}")]
+    IntegrityMismatch(String, IntegrityError),
+    #[error("{0} isn't in the package cache, run without --offline to download it")]
+    NotInOfflineCache(String),
 }
 
 impl From<std::io::Error> for AddCommandError {
@@ -64,271 +75,689 @@ pub struct Package {
     name: String,
     tarball_url: String,
     version: String,
+    integrity: Option<String>,
+    // Dependency specs from the version's manifest
+    dependencies: BTreeMap<String, String>,
+    // Names among the dependencies that are optional
+    optional_dependencies: BTreeSet<String>,
 }
 
-pub struct PackageRaw {
-    pub name: String,
-    pub version: String,
+// The package.json fields `add` can save a dependency to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SaveType {
+    #[default]
+    Prod,
+    Dev,
+    Optional,
+    // Saved to devDependencies too, so the project itself gets it installed
+    Peer,
 }
 
-async fn get_pkg_details(package_name: &str) -> Result<Package, AddCommandError> {
-    let url = format!("https://registry.npmjs.org/{}", package_name);
-    let package_metadata = reqwest::get(&url)
-        .await
-        .map_err(|e| AddCommandError::FailedToRetrievePackageData(e))?
-        .json::<Value>()
-        .await
-        .map_err(|e| AddCommandError::FailedToParsePackageMeta(e))?;
-    if let Some(latest_version) = package_metadata["dist-tags"]["latest"].as_str() {
-        if let Some(tarball_url) =
-            package_metadata["versions"][latest_version]["dist"]["tarball"].as_str()
-        {
-            return Ok(Package {
-                name: package_name.to_string(),
-                tarball_url: tarball_url.to_string(),
-                version: latest_version.to_string(),
-            });
+impl SaveType {
+    fn fields(&self) -> &'static [&'static str] {
+        match self {
+            SaveType::Prod => &["dependencies"],
+            SaveType::Dev => &["devDependencies"],
+            SaveType::Optional => &["optionalDependencies"],
+            SaveType::Peer => &["peerDependencies", "devDependencies"],
         }
     }
-    Err(AddCommandError::NoValidTarballUrl(package_name.to_string()))
 }
 
-async fn get_pkg_details_with_version(package_name: &str, version: &str) -> Result<Package, AddCommandError> {
-    let url = format!("https://registry.npmjs.org/{}/{}", package_name, version);
-    let package_metadata = reqwest::get(&url)
+// Every package.json field listing dependencies
+pub const DEPENDENCY_FIELDS: [&str; 4] = ["dependencies", "devDependencies", "optionalDependencies", "peerDependencies"];
+
+// Outside of online mode a cached packument is used however old it is
+async fn fetch_packument(registry: &Registry, package_name: &str) -> Result<Arc<Value>, AddCommandError> {
+    let allow_stale = registry.network_mode != NetworkMode::Online;
+    registry
+        .packuments
+        .get_or_try_init(package_name, || load_packument(registry, package_name, allow_stale))
         .await
-        .map_err(|e| AddCommandError::FailedToRetrievePackageData(e))?
-        .json::<Value>()
+}
+
+// Skips the cache unless it is within its TTL, for when a stale packument
+// turned out not to have the version asked for
+async fn refetch_packument(registry: &Registry, package_name: &str) -> Result<Arc<Value>, AddCommandError> {
+    registry
+        .refreshed_packuments
+        .get_or_try_init(package_name, || load_packument(registry, package_name, false))
+        .await
+}
+
+async fn load_packument(registry: &Registry, package_name: &str, allow_stale: bool) -> Result<Arc<Value>, AddCommandError> {
+    let url = registry.packument_url(package_name);
+    let cached = registry.metadata_cache.load(&url);
+    if let Some(cached) = cached
+        .as_ref()
+        .filter(|cached| allow_stale || registry.metadata_cache.is_fresh(cached))
+    {
+        return Ok(Arc::new(cached.packument.clone()));
+    }
+    if registry.network_mode == NetworkMode::Offline {
+        return Err(AddCommandError::NotInOfflineCache(package_name.to_string()));
+    }
+
+    let revalidation = registry
+        .fetch_packument(&url, package_name, cached.as_ref())
         .await
-        .map_err(|e| AddCommandError::FailedToParsePackageMeta(e))?;
-    if let Some(tarball_url) = package_metadata["dist"]["tarball"].as_str() {
+        .map_err(AddCommandError::FailedToRetrievePackageData)?;
+    let fresh = match (revalidation, cached) {
+        (Revalidation::NotModified, Some(cached)) => {
+            CachedPackument::new(cached.packument, cached.etag, cached.last_modified)
+        }
+        (Revalidation::Modified { body, etag, last_modified }, _) => {
+            let packument = serde_json::from_slice(&body).map_err(AddCommandError::FailedToParsePackageMeta)?;
+            CachedPackument::new(packument, etag, last_modified)
+        }
+        (Revalidation::NotModified, None) => unreachable!("304 is only accepted with a cached copy"),
+    };
+    registry.metadata_cache.store(&url, &fresh);
+    Ok(Arc::new(fresh.packument))
+}
+
+// Picks the version a spec refers to from a packument. Dist-tags win, then the
+// `latest` tag if it satisfies the range (like npm), then the highest match.
+fn resolve_version(package_name: &str, packument: &Value, spec: &str) -> Result<String, AddCommandError> {
+    let spec = spec.trim();
+    let spec = if spec.is_empty() { "latest" } else { spec };
+    if let Some(tagged) = packument["dist-tags"][spec].as_str() {
+        return Ok(tagged.to_string());
+    }
+
+    let range = Range::parse(spec)?;
+    if let Some(latest) = packument["dist-tags"]["latest"].as_str() {
+        if Version::parse(latest).is_ok_and(|latest| range.satisfies(&latest)) {
+            return Ok(latest.to_string());
+        }
+    }
+    let versions = packument["versions"]
+        .as_object()
+        .ok_or_else(|| AddCommandError::NoValidTarballUrl(package_name.to_string()))?;
+    range
+        .max_satisfying(versions.keys().map(String::as_str))
+        .map(|version| version.to_string())
+        .ok_or_else(|| AddCommandError::NoMatchingVersion(package_name.to_string(), spec.to_string()))
+}
+
+async fn get_pkg_details(registry: &Registry, package_name: &str) -> Result<Package, AddCommandError> {
+    get_pkg_details_with_version(registry, package_name, "latest").await
+}
+
+async fn get_pkg_details_with_version(registry: &Registry, package_name: &str, version: &str) -> Result<Package, AddCommandError> {
+    let mut packument = fetch_packument(registry, package_name).await?;
+    let version = match resolve_version(package_name, &packument, version) {
+        Err(AddCommandError::NoMatchingVersion(..)) if registry.network_mode == NetworkMode::PreferOffline => {
+            packument = refetch_packument(registry, package_name).await?;
+            resolve_version(package_name, &packument, version)?
+        }
+        result => result?,
+    };
+    let manifest = &packument["versions"][&version];
+    if let Some(tarball_url) = manifest["dist"]["tarball"].as_str() {
         return Ok(Package {
             name: package_name.to_string(),
             tarball_url: tarball_url.to_string(),
-            version: version.to_string(),
+            integrity: manifest["dist"]["integrity"]
+                .as_str()
+                .map(str::to_string)
+                .or_else(|| manifest["dist"]["shasum"].as_str().and_then(integrity::from_shasum)),
+            // Publishing copies optionalDependencies into dependencies, but not
+            // every registry does
+            dependencies: manifest_dependencies(manifest, "dependencies")
+                .into_iter()
+                .chain(manifest_dependencies(manifest, "optionalDependencies"))
+                .collect(),
+            optional_dependencies: manifest_dependencies(manifest, "optionalDependencies").into_keys().collect(),
+            version,
         });
     }
     Err(AddCommandError::NoValidTarballUrl(package_name.to_string()))
 }
 
-fn add_to_package_json(package: Package, current_dir: &PathBuf) {
+fn manifest_dependencies(manifest: &Value, field: &str) -> BTreeMap<String, String> {
+    manifest[field]
+        .as_object()
+        .map(|dependencies| {
+            dependencies
+                .iter()
+                .map(|(name, spec)| (name.clone(), spec.as_str().unwrap_or("latest").to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Splits `name@spec` into its parts. Scoped names start with `@` themselves, so
+// only an `@` after the first character separates the spec.
+pub fn parse_package_spec(input: &str) -> (&str, Option<&str>) {
+    match input.get(1..).and_then(|rest| rest.find('@')) {
+        Some(position) => (&input[..=position], Some(&input[position + 2..])),
+        None => (input, None),
+    }
+}
+
+// The dependencies package.json asks to install. A name in several fields
+// counts as optional over production over dev, like npm does.
+pub fn read_package_json_dependencies(current_dir: &Path) -> Result<DependencySpecs, Box<dyn Error + Send + Sync>> {
+    let package_json_str = std::fs::read_to_string(current_dir.join("package.json"))?;
+    let package_json_value: Value = serde_json::from_str(&package_json_str)?;
+    let mut specs = DependencySpecs::default();
+    for field in ["devDependencies", "dependencies", "optionalDependencies"] {
+        for (name, spec) in manifest_dependencies(&package_json_value, field) {
+            specs.dev.remove(&name);
+            if field == "devDependencies" {
+                specs.dev.insert(name.clone());
+            }
+            if field == "optionalDependencies" {
+                specs.optional.insert(name.clone());
+            }
+            specs.specifiers.insert(name, spec);
+        }
+    }
+    Ok(specs)
+}
+
+// Saves a dependency to the fields `save_type` picks, taking it out of the
+// others so re-adding moves it
+fn add_to_package_json(package_name: &str, spec: &str, current_dir: &Path, save_type: SaveType) {
     let package_json_path = current_dir.join("package.json");
-    let mut package_json = std::fs::read_to_string(&package_json_path).unwrap();
+    let package_json = std::fs::read_to_string(&package_json_path).unwrap();
     let package_json_value: Value = serde_json::from_str(&package_json).unwrap();
     let mut package_json_object = package_json_value
         .as_object()
         .unwrap()
         .clone();
-    let dependencies = package_json_object
-        .entry("dependencies")
-        .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
-    if let Some(dep_object) = dependencies.as_object_mut() {
-        dep_object.insert(
-            package.name,
-            serde_json::Value::String(package.version),
-        );
+    for field in DEPENDENCY_FIELDS {
+        let Some(dependencies) = package_json_object.get_mut(field).and_then(Value::as_object_mut) else {
+            continue;
+        };
+        if dependencies.remove(package_name).is_some() && dependencies.is_empty() {
+            package_json_object.remove(field);
+        }
+    }
+    for field in save_type.fields() {
+        let dependencies = package_json_object
+            .entry(*field)
+            .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        if let Some(dep_object) = dependencies.as_object_mut() {
+            dep_object.insert(
+                package_name.to_string(),
+                serde_json::Value::String(spec.to_string()),
+            );
+        }
     }
     let updated_json = serde_json::to_string_pretty(&package_json_object).unwrap();
     std::fs::write(package_json_path, updated_json).unwrap();
 }
 
-#[async_recursion]
+// Adds packages to the package.json of the project, or of the workspace member
+// it targets, then installs. Workspace members are added with the workspace
+// protocol instead of being looked up in the registry.
 pub async fn add_packages_with_dependencies_from_names(
     package_names: &[String],
-    current_dir: Arc<PathBuf>,
-    cache_dir: Arc<PathBuf>,
+    save_type: SaveType,
+    project: &Project,
+    store: Arc<Store>,
+    registry: Arc<Registry>,
+    config: &Config,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut packages = Vec::new();
+    let workspaces = workspace::find_workspaces(&project.root)?;
     for package_name in package_names {
+        let (name, spec) = parse_package_spec(package_name);
+        if spec.is_none() && workspaces.iter().any(|workspace| workspace.name == name) {
+            add_to_package_json(name, "workspace:^", &project.dir, save_type);
+            continue;
+        }
         //Get version if specified
-        let package = if package_name.contains("@") {
-            let package_split: Vec<&str> = package_name.split('@').collect();
-            //if @latest is specified, get latest version
-            if package_split[1] == "latest" {
-                get_pkg_details(package_split[0]).await?
-            } else {
-                get_pkg_details_with_version(package_split[0], package_split[1]).await?
-            }
-        } else {
-            get_pkg_details(package_name).await?
+        let package = match spec {
+            Some(version) => get_pkg_details_with_version(&registry, name, version).await?,
+            None => get_pkg_details(&registry, name).await?,
         };
-        packages.push(package);
+        add_to_package_json(&package.name, &package.version, &project.dir, save_type);
     }
-    add_packages_with_dependencies(&packages, current_dir, cache_dir).await
+    install_project(Arc::new(project.root.clone()), store, registry, config).await
 }
 
-#[async_recursion]
-pub async fn add_packages_with_dependencies_from_names_with_version(
-    package_names: &[PackageRaw],
+// Installs the dependencies of the project and all of its workspace members
+// with a single resolution, reusing the lockfile while it is up to date
+pub async fn install_project(
     current_dir: Arc<PathBuf>,
-    cache_dir: Arc<PathBuf>,
+    store: Arc<Store>,
+    registry: Arc<Registry>,
+    config: &Config,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut packages = Vec::new();
-    for package in package_names {
-        let package = get_pkg_details_with_version(&package.name, &package.version).await?;
-        packages.push(package);
+    let workspaces = workspace::find_workspaces(&current_dir)?;
+    let specifiers = project_specifiers(&current_dir, &workspaces)?;
+
+    let previous = Lockfile::load(&current_dir)?;
+    let lockfile = match previous {
+        Some(lockfile) if lockfile.is_up_to_date(&specifiers) => {
+            println!("Lockfile is up to date, skipping resolution.");
+            lockfile
+        }
+        previous => resolve_dependency_tree(&specifiers, &workspaces, previous.as_ref(), &registry).await?,
+    };
+
+    add_packages_with_dependencies(&lockfile, current_dir.clone(), store, registry, config).await?;
+    lockfile.save(&current_dir)?;
+    Ok(())
+}
+
+// The dependency specs of the project and of every workspace member
+fn project_specifiers(current_dir: &Path, workspaces: &[Workspace]) -> Result<ImporterSpecifiers, Box<dyn Error + Send + Sync>> {
+    let mut specifiers = ImporterSpecifiers::new();
+    let importers = std::iter::once((ROOT_IMPORTER, current_dir))
+        .chain(workspaces.iter().map(|workspace| (workspace.path.as_str(), workspace.dir.as_path())));
+    for (path, dir) in importers {
+        specifiers.insert(path.to_string(), read_package_json_dependencies(dir)?);
     }
-    add_packages_with_dependencies(&packages, current_dir, cache_dir).await
+    Ok(specifiers)
 }
 
-#[async_recursion]
-pub async fn add_packages_with_dependencies(
-    packages: &[Package],
+// Installs exactly what the lockfile records without resolving anything or
+// touching package.json and the lock, for CI. The node_modules of the root and
+// every workspace member are wiped first so nothing left over from earlier
+// installs leaks into the tree.
+pub async fn install_frozen_lockfile(
     current_dir: Arc<PathBuf>,
-    cache_dir: Arc<PathBuf>,
+    store: Arc<Store>,
+    registry: Arc<Registry>,
+    config: &Config,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut tasks = Vec::new();
+    let workspaces = workspace::find_workspaces(&current_dir)?;
+    let specifiers = project_specifiers(&current_dir, &workspaces)?;
+    let lockfile = Lockfile::load(&current_dir)?.ok_or(LockfileError::Missing)?;
+    let differences = lockfile.differences(&specifiers);
+    if !differences.is_empty() {
+        return Err(LockfileError::OutOfDate(differences).into());
+    }
 
-    for package in packages {
-        let current_dir_clone = Arc::clone(&current_dir);
-        let cache_dir_clone = Arc::clone(&cache_dir);
-        let package_clone = Package {
-            name: package.name.clone(),
-            tarball_url: package.tarball_url.clone(),
-            version: package.version.clone(),
-        };
+    remove_link(&current_dir.join("node_modules"))?;
+    for workspace in &workspaces {
+        remove_link(&workspace.dir.join("node_modules"))?;
+    }
+    add_packages_with_dependencies(&lockfile, current_dir, store, registry, config).await
+}
 
-        let task = tokio::spawn(async move {
-            let tarball_url = package_clone.tarball_url.clone();
-            let file_name = tarball_url.rsplit('/').next()
-                .ok_or_else(|| DownloadError::ExtractionFailed(std::io::Error::new(std::io::ErrorKind::Other, "Failed to extract file name from URL")))?;
+// Who asked for a dependency during resolution
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Dependent {
+    // The project or a workspace member, by its path
+    Importer(String),
+    // A package, by `name@version`
+    Package(String),
+}
+
+// A dependency still to resolve during resolution
+struct PendingDependency {
+    dependent: Dependent,
+    name: String,
+    spec: String,
+    // The optional edge, `dependent` and name, this dependency was reached
+    // through, if any. Its own when it is optional itself.
+    optional_edge: Option<(Dependent, String)>,
+}
 
-            let package_name = file_name.splitn(2, ".tgz").next()
-                .ok_or_else(|| DownloadError::ExtractionFailed(std::io::Error::new(std::io::ErrorKind::Other, "Invalid file name format")))?;
-            let package_path = cache_dir_clone.join(format!("node_modules/{}", package_name));
+// Resolves every package reachable from `specifiers` into a lockfile. Versions
+// already in `previous` are reused when they still satisfy the spec, so adding
+// one package doesn't silently upgrade the rest of the tree. Dependencies on
+// workspace members become links to them, and optional dependencies that fail
+// to resolve, or have something failing to resolve under them, are left out.
+pub async fn resolve_dependency_tree(
+    specifiers: &ImporterSpecifiers,
+    workspaces: &[Workspace],
+    previous: Option<&Lockfile>,
+    registry: &Arc<Registry>,
+) -> Result<Lockfile, Box<dyn Error + Send + Sync>> {
+    let mut lockfile = Lockfile::new(specifiers.get(ROOT_IMPORTER).cloned().unwrap_or_default());
+    for workspace in workspaces {
+        lockfile.workspaces.insert(workspace.path.clone(), Importer {
+            name: Some(workspace.name.clone()),
+            specs: specifiers.get(&workspace.path).cloned().unwrap_or_default(),
+            ..Importer::default()
+        });
+    }
+    // Offline resolution carries on past packages the cache can't provide so
+    // they can all be reported at once
+    let mut missing: Vec<String> = Vec::new();
+    // (name, spec) pairs to the version they resolved to
+    let mut resolved: HashMap<(String, String), String> = HashMap::new();
+    let mut pending: Vec<PendingDependency> = Vec::new();
+    // Optional edges left out because something under them failed to
+    // resolve, and the dependencies that failed
+    let mut dropped: BTreeSet<(Dependent, String)> = BTreeSet::new();
+    let mut failures: Vec<(Vec<Dependent>, AddCommandError)> = Vec::new();
+    for (path, importer_specs) in specifiers {
+        for (name, spec) in &importer_specs.specifiers {
+            match workspace::find_linked(workspaces, name, spec)? {
+                Some(member) => {
+                    if let Some(importer) = lockfile.importer_mut(path) {
+                        importer.links.insert(name.clone(), member.path.clone());
+                    }
+                }
+                None => {
+                    let dependent = Dependent::Importer(path.clone());
+                    let optional_edge = importer_specs
+                        .optional
+                        .contains(name)
+                        .then(|| (dependent.clone(), name.clone()));
+                    pending.push(PendingDependency {
+                        dependent,
+                        name: name.clone(),
+                        spec: spec.clone(),
+                        optional_edge,
+                    });
+                }
+            }
+        }
+    }
 
-            let local_package_path = current_dir_clone.join(format!("node_modules/{}", package_name));
-            if local_package_path.exists() {
-                return Ok::<(), Box<dyn Error + Send + Sync>>(());
+    while !pending.is_empty() {
+        let mut tasks = Vec::new();
+        for PendingDependency { name, spec, .. } in &pending {
+            let request = (name.clone(), spec.clone());
+            if resolved.contains_key(&request) || tasks.iter().any(|(queued, _)| queued == &request) {
+                continue;
             }
+            if let Some((previous, locked)) = previous
+                .and_then(|previous| previous.find_satisfying(name, spec).map(|locked| (previous, locked)))
+            {
+                lockfile.copy_subtree(previous, &package_key(&locked.name, &locked.version));
+                resolved.insert(request, locked.version.clone());
+                continue;
+            }
+            let (name, spec) = request.clone();
+            let registry = Arc::clone(registry);
+            let task = tokio::spawn(async move { get_pkg_details_with_version(&registry, &name, &spec).await });
+            tasks.push((request, task));
+        }
 
-            add_to_package_json(package_clone.clone(), &current_dir_clone);
-            if package_path.exists() {
-                println!("Package {} already installed, using cache.", format!("{}@{}", package_clone.name, package_clone.version));
-                folder_symlink(&current_dir_clone, &cache_dir_clone, package_name);
-            } else {
-                println!("Downloading package {}", format!("{}@{}", package_clone.name, package_clone.version));
-                download_and_extract_with_reqwest(&tarball_url, &current_dir_clone, &cache_dir_clone).await?;
+        let mut next = Vec::new();
+        for (request, task) in tasks {
+            let requesters = || pending.iter().filter(|entry| (&entry.name, &entry.spec) == (&request.0, &request.1));
+            let package = match task.await? {
+                Ok(package) => package,
+                // Skipped when everything asking for it is under an optional
+                // edge, which gets dropped along with its whole subtree. The
+                // packages asking for it for real have to end up unused too,
+                // which is only known once the whole tree is resolved.
+                Err(e) if requesters().all(|entry| entry.optional_edge.is_some()) => {
+                    let mut needed_by = Vec::new();
+                    for entry in requesters() {
+                        let Some((dependent, name)) = entry.optional_edge.clone() else {
+                            continue;
+                        };
+                        match &dependent {
+                            Dependent::Package(key) => println!("Skipping optional dependency {} of {}: {}", name, key, e),
+                            Dependent::Importer(_) => println!("Skipping optional dependency {}: {}", name, e),
+                        }
+                        if (&dependent, &name) != (&entry.dependent, &entry.name) {
+                            needed_by.push(entry.dependent.clone());
+                        }
+                        dropped.insert((dependent, name));
+                    }
+                    failures.push((needed_by, e));
+                    continue;
+                }
+                Err(AddCommandError::NotInOfflineCache(_) | AddCommandError::NoMatchingVersion(..))
+                    if registry.network_mode == NetworkMode::Offline =>
+                {
+                    missing.push(package_key(&request.0, &request.1));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // Anything the package depends on is under an optional edge only
+            // when every way to it so far is
+            let mut inherited = requesters().map(|entry| entry.optional_edge.clone());
+            let first = inherited.next().flatten();
+            let inherited = if inherited.all(|edge| edge.is_some()) { first } else { None };
+            let key = package_key(&package.name, &package.version);
+            resolved.insert(request, package.version.clone());
+            if lockfile.packages.contains_key(&key) {
+                continue;
             }
-            install_package_dependencies(&package_clone.name, &tarball_url, &current_dir_clone, &cache_dir_clone).await?;
-            Ok(())
-        });
+            for (name, spec) in &package.dependencies {
+                let dependent = Dependent::Package(key.clone());
+                let optional_edge = if package.optional_dependencies.contains(name) {
+                    Some((dependent.clone(), name.clone()))
+                } else {
+                    inherited.clone()
+                };
+                next.push(PendingDependency {
+                    dependent,
+                    name: name.clone(),
+                    spec: spec.clone(),
+                    optional_edge,
+                });
+            }
+            lockfile.packages.insert(key, LockedPackage {
+                name: package.name,
+                version: package.version,
+                resolved: package.tarball_url,
+                integrity: package.integrity,
+                dependencies: BTreeMap::new(),
+                optional_dependencies: package.optional_dependencies,
+                dev: false,
+                optional: false,
+            });
+        }
 
-        tasks.push(task);
+        for PendingDependency { dependent, name, spec, .. } in pending {
+            let Some(version) = resolved.get(&(name.clone(), spec)).cloned() else {
+                continue;
+            };
+            let Some(edges) = dependent_edges(&mut lockfile, &dependent) else {
+                continue;
+            };
+            edges.insert(name, version);
+        }
+        pending = next;
     }
 
-    for task in tasks {
-        task.await??;
+    if !missing.is_empty() {
+        missing.sort();
+        missing.dedup();
+        return Err(AddCommandError::MissingFromOfflineCache(missing).into());
     }
+    if !dropped.is_empty() {
+        for (dependent, name) in &dropped {
+            if let Some(edges) = dependent_edges(&mut lockfile, dependent) {
+                edges.remove(name);
+            }
+        }
+        lockfile = lockfile.installable(false, &BTreeSet::new());
+        for (needed_by, e) in failures {
+            let still_needed = needed_by
+                .iter()
+                .any(|dependent| matches!(dependent, Dependent::Package(key) if lockfile.packages.contains_key(key)));
+            if still_needed {
+                return Err(e.into());
+            }
+        }
+    }
+    lockfile.mark_dev_and_optional();
+    Ok(lockfile)
+}
 
-    Ok(())
+// The resolved dependencies of whoever asked for a dependency
+fn dependent_edges<'a>(lockfile: &'a mut Lockfile, dependent: &Dependent) -> Option<&'a mut BTreeMap<String, String>> {
+    match dependent {
+        Dependent::Package(key) => lockfile.packages.get_mut(key).map(|package| &mut package.dependencies),
+        Dependent::Importer(path) => lockfile.importer_mut(path).map(|importer| &mut importer.dependencies),
+    }
 }
 
-#[async_recursion]
-pub async fn install_package_dependencies(
-    package_name: &str,
-    url: &str,
-    current_dir: &Arc<PathBuf>,
-    cache_dir: &Arc<PathBuf>,
+// Makes sure every locked package is in the store, then lays them out in the
+// project's node_modules. devDependencies are left out in production mode, and
+// optional packages that can't be downloaded or built are skipped.
+pub async fn add_packages_with_dependencies(
+    lockfile: &Lockfile,
+    current_dir: Arc<PathBuf>,
+    store: Arc<Store>,
+    registry: Arc<Registry>,
+    config: &Config,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let file_name = url.rsplit('/').next()
-        .ok_or_else(|| DownloadError::ExtractionFailed(std::io::Error::new(std::io::ErrorKind::Other, "Failed to extract file name from URL")))?;
-    let downloaded_package_name = file_name.splitn(2, ".tgz").next().ok_or_else(|| DownloadError::ExtractionFailed(std::io::Error::new(std::io::ErrorKind::Other, "Invalid file name format")))?;
-
-    let package_json_path = cache_dir
-        .join(format!("node_modules/{}/package.json", downloaded_package_name))
-        .into_os_string()
-        .into_string()
-        .unwrap()
-        .replace("\\", "/");
+    let mut lockfile = lockfile.installable(config.production, &BTreeSet::new());
+    let mut failed = BTreeSet::new();
+    if registry.network_mode == NetworkMode::Offline {
+        let mut missing: Vec<String> = Vec::new();
+        for package in lockfile.packages.values() {
+            if cached_index(&store, package).await.is_some() {
+                continue;
+            }
+            let key = package_key(&package.name, &package.version);
+            if package.optional {
+                println!("Skipping optional dependency {}, it isn't in the package cache", key);
+                failed.insert(key);
+            } else {
+                missing.push(key);
+            }
+        }
+        if !missing.is_empty() {
+            return Err(AddCommandError::MissingFromOfflineCache(missing).into());
+        }
+    }
+
+    let mut tasks = Vec::new();
 
-    let package_json = match std::fs::read_to_string(Path::new(&package_json_path)) {
-        Ok(content) => content,
-        Err(_) => {
-            println!("package.json not found for package {}", package_name);
-            return Ok(())
+    for package in lockfile.packages.values() {
+        let key = package_key(&package.name, &package.version);
+        if failed.contains(&key) {
+            continue;
         }
-    };
-    let package: Value = serde_json::from_str(&package_json)?;
-
-    if let Some(dependencies) = package["dependencies"].as_object() {
-        let mut dep_packages = Vec::new();
-        for (name, version) in dependencies.iter() {
-            let version_str = version.as_str().unwrap();
-            let package_detail = if version_str.starts_with("^") {
-                get_pkg_details(name).await?
-            } else {
-                get_pkg_details_with_version(name, version_str).await?
-            };
-            dep_packages.push(package_detail);
+        let store_clone = Arc::clone(&store);
+        let registry_clone = Arc::clone(&registry);
+        let package_clone = package.clone();
+
+        let task = tokio::spawn(async move {
+            let index = ensure_cached(&registry_clone, &store_clone, &package_clone).await;
+            (package_clone, index)
+        });
+
+        tasks.push(task);
+    }
+
+    let mut indexes = HashMap::new();
+    for task in tasks {
+        let (package, index) = task.await?;
+        let key = package_key(&package.name, &package.version);
+        match index {
+            Ok(index) => {
+                indexes.insert(key, index);
+            }
+            Err(e) if package.optional => {
+                println!("Skipping optional dependency {}: {}", key, e);
+                failed.insert(key);
+            }
+            Err(e) => return Err(e),
         }
-        add_packages_with_dependencies(&dep_packages, Arc::clone(current_dir), Arc::clone(cache_dir)).await?;
+    }
+    if !failed.is_empty() {
+        lockfile = lockfile.installable(false, &failed);
     }
 
+    let imported = layout::link_packages(&lockfile, &current_dir, &store, &indexes)?;
+    if !config.ignore_scripts {
+        lifecycle::run_dependency_scripts(&lockfile, &imported, &current_dir, config)?;
+    }
     Ok(())
 }
 
+// Makes sure a locked package is in the store and returns its index.
+// Concurrent callers asking for the same package share a single download.
+pub async fn ensure_cached(
+    registry: &Registry,
+    store: &Store,
+    package: &LockedPackage,
+) -> Result<Arc<PackageIndex>, Box<dyn Error + Send + Sync>> {
+    let key = package_key(&package.name, &package.version);
+    let index = registry
+        .downloads
+        .get_or_try_init(&key, || async {
+            if let Some(index) = cached_index(store, package).await {
+                println!("Package {} already installed, using cache.", key);
+                return Ok(Arc::new(index));
+            }
+            if registry.network_mode == NetworkMode::Offline {
+                return Err(DownloadError::NotInOfflineCache(key.clone()).into());
+            }
+            if store.load_index(&package.name, &package.version).is_some() {
+                println!("Cached {} doesn't match its integrity or its files are damaged, downloading again.", key);
+            }
+            println!("Downloading package {}", key);
+            let tarball_url = registry.tarball_url(&package.name, &package.resolved);
+            let index = download_into_store(registry, store, package, &tarball_url).await?;
+            Ok::<_, Box<dyn Error + Send + Sync>>(Arc::new(index))
+        })
+        .await?;
+    Ok(index)
+}
 
+pub fn cache_key(package_name: &str, version: &str) -> String {
+    format!("{}-{}", package_name, version)
+}
 
-pub async fn download_and_extract_with_reqwest(
-    url: &str,
-    current_dir: &Arc<PathBuf>,
-    cache_dir: &Arc<PathBuf>,
-) -> Result<(), DownloadError> {
-    let response = reqwest::get(url).await?.bytes().await?;
-    let cursor = Cursor::new(response);
-    let tar_gz = GzDecoder::new(cursor);
-    let mut archive = Archive::new(tar_gz);
-
-    let url_split: Vec<&str> = url.split('/').collect();
-    let file_name = url_split
-        .last()
-        .ok_or(DownloadError::ExtractionFailed(std::io::Error::new(std::io::ErrorKind::Other, "Failed to extract file name from URL")))?;
-
-    let package_name = file_name.split(".tgz").next().ok_or(DownloadError::ExtractionFailed(std::io::Error::new(std::io::ErrorKind::Other, "Invalid file name format")))?;
-    let package_path = cache_dir.join("node_modules/".to_owned()+package_name);
-    fs::create_dir_all(&package_path)?;
-
-    for file in archive.entries()? {
-        let mut file = file?;
-        if file.header().entry_type() != EntryType::Regular
-            && file.header().entry_type() != EntryType::Directory
-        {
-            continue;
-        }
-        let path: std::borrow::Cow<'_, Path> = file.path()?;
-        let mut components: std::path::Components<'_> = path.components();
-        components.next();
-        let new_path = package_path.join(components.as_path());
-        if let Some(parent) = new_path.parent() {
-            fs::create_dir_all(parent)?;
-        }
-        if file.header().entry_type() == EntryType::Directory {
-            fs::create_dir_all(&new_path)?;
-        } else {
-            file.unpack(new_path)?;
+// Where a package lives inside a node_modules directory. Scoped packages get
+// a directory per scope, `node_modules/@scope/name`.
+pub fn package_path(node_modules: &Path, package_name: &str) -> PathBuf {
+    package_name
+        .split('/')
+        .fold(node_modules.to_path_buf(), |path, segment| path.join(segment))
+}
+
+// Removes a package from a node_modules directory, along with its scope
+// directory once that is empty
+pub fn remove_package_link(node_modules: &Path, package_name: &str) -> std::io::Result<()> {
+    let link = package_path(node_modules, package_name);
+    remove_link(&link)?;
+    if package_name.starts_with('@') {
+        if let Some(scope_dir) = link.parent() {
+            // Fails while other packages of the scope are still installed
+            let _ = fs::remove_dir(scope_dir);
         }
     }
-
-    folder_symlink(current_dir, cache_dir, package_name);
-    
     Ok(())
 }
 
-use std::os::windows::fs::symlink_dir;
-pub fn folder_symlink(current_dir: &PathBuf, cache_dir: &PathBuf, downloadedpackagename: &str) {
-    let local_package_name = if let Some(pos) = downloadedpackagename.rfind('-') {
-        downloadedpackagename[..pos].to_string()
-    } else {
-        downloadedpackagename.to_string()
-    };
-    // if symlink already exists remove it / replace it 
-    // could be a better way to do this in the future for optimization
-    // but for now this way we can ensure that the symlink is always up to date
-    // and we don't have to worry about checking if it exists or not
-    let _ = std::fs::remove_dir(current_dir.join("node_modules").join(&local_package_name));
-    symlink_dir(
-        cache_dir.join("node_modules").join(downloadedpackagename),
-        current_dir.join("node_modules").join(&local_package_name),
-    ).unwrap_or_else(|e| eprintln!("Failed to create symlink for {}: {}", downloadedpackagename, e));
+// The store's index for a package, as long as it was recorded for the
+// integrity the lockfile expects and the stored files still hash to what the
+// index says they are. Hashing is blocking work, so it runs on the blocking
+// pool rather than holding up the downloads running alongside.
+async fn cached_index(store: &Store, package: &LockedPackage) -> Option<PackageIndex> {
+    let (store, package) = (store.clone(), package.clone());
+    tokio::task::spawn_blocking(move || {
+        store
+            .load_index(&package.name, &package.version)
+            .filter(|index| package.integrity.as_ref().is_none_or(|integrity| &index.integrity == integrity))
+            .filter(|index| store.verify_files(index))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+pub async fn download_into_store(
+    registry: &Registry,
+    store: &Store,
+    package: &LockedPackage,
+    url: &str,
+) -> Result<PackageIndex, DownloadError> {
+    let response = registry.fetch(url, &package.name).await?;
+    // Hashing and extracting are blocking work, done on the blocking pool so
+    // the other fetches keep going meanwhile
+    let (store, package, url) = (store.clone(), package.clone(), url.to_string());
+    tokio::task::spawn_blocking(move || {
+        // Verify before anything touches the disk
+        let integrity = match package.integrity.as_deref() {
+            Some(integrity) => {
+                integrity::verify(&response, integrity).map_err(|e| DownloadError::IntegrityMismatch(url, e))?;
+                integrity.to_string()
+            }
+            None => integrity::sri("sha512", &response).unwrap_or_default(),
+        };
+        Ok(store.add_tarball(&package.name, &package.version, &response, &integrity)?)
+    })
+    .await
+    .map_err(|e| DownloadError::ExtractionFailed(std::io::Error::other(e)))?
 }
@@ -0,0 +1,69 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::build_policy::{BuildPolicy, PendingBuilds};
+use crate::config::Config;
+use crate::lifecycle::run_dependency_scripts;
+use crate::lockfile::Lockfile;
+
+// Lets the user decide on the dependencies whose install scripts were blocked,
+// either the ones named on the command line or by asking about each of them,
+// then runs the scripts of the approved ones
+pub fn approve_builds(current_dir: &Path, config: &Config, package_names: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let pending = PendingBuilds::load(current_dir);
+    if pending.is_empty() && package_names.is_empty() {
+        println!("No packages are waiting for their install scripts to be approved");
+        return Ok(());
+    }
+    let lockfile = Lockfile::load(current_dir)?.ok_or("qnpm-lock.json not found, run qnpm install first")?;
+
+    let (allowed, ignored) = if package_names.is_empty() {
+        let blocked: BTreeSet<&str> = pending
+            .keys()
+            .filter_map(|key| lockfile.packages.get(key))
+            .map(|package| package.name.as_str())
+            .collect();
+        ask(&blocked)?
+    } else {
+        (package_names.to_vec(), Vec::new())
+    };
+    if allowed.is_empty() && ignored.is_empty() {
+        return Ok(());
+    }
+
+    BuildPolicy::record(current_dir, &allowed, &ignored)?;
+    for name in &allowed {
+        println!("Allowed install scripts of {}", name);
+    }
+    for name in &ignored {
+        println!("Ignoring install scripts of {}", name);
+    }
+    if !config.ignore_scripts {
+        run_dependency_scripts(&lockfile, &[], current_dir, config)?;
+    }
+    Ok(())
+}
+
+// "y" allows a package, "n" stops asking about it, anything else leaves it
+// blocked for now
+fn ask(blocked: &BTreeSet<&str>) -> io::Result<(Vec<String>, Vec<String>)> {
+    let mut allowed = Vec::new();
+    let mut ignored = Vec::new();
+    let mut lines = io::stdin().lock().lines();
+    for name in blocked {
+        print!("Allow install scripts of {}? [y = allow, n = never, enter = skip] ", name);
+        io::stdout().flush()?;
+        let Some(answer) = lines.next().transpose()? else {
+            println!();
+            break;
+        };
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => allowed.push(name.to_string()),
+            "n" | "no" => ignored.push(name.to_string()),
+            _ => {}
+        }
+    }
+    Ok((allowed, ignored))
+}
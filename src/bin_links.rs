@@ -0,0 +1,212 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::add::package_path;
+use crate::link::{relative_path, remove_link};
+
+// Links the executables of packages installed in `node_modules` into
+// `node_modules/.bin`. When several packages provide the same command the one
+// listed last wins.
+pub fn link_bins(node_modules: &Path, package_names: &[String]) -> io::Result<()> {
+    let bin_dir = node_modules.join(".bin");
+    remove_dangling(&bin_dir)?;
+
+    let mut commands: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for package_name in package_names {
+        let package_dir = package_path(node_modules, package_name);
+        for (command, target) in package_bins(&package_dir) {
+            commands.insert(command, target);
+        }
+    }
+    if commands.is_empty() {
+        return Ok(());
+    }
+
+    fs::create_dir_all(&bin_dir)?;
+    for (command, target) in commands {
+        if target.is_file() {
+            link_bin(&target, &bin_dir.join(command))?;
+        }
+    }
+    Ok(())
+}
+
+// The commands an installed package declares, see `manifest_bins`
+pub fn package_bins(package_dir: &Path) -> Vec<(String, PathBuf)> {
+    let Ok(contents) = fs::read(package_dir.join("package.json")) else {
+        return Vec::new();
+    };
+    let Ok(manifest) = serde_json::from_slice::<Value>(&contents) else {
+        return Vec::new();
+    };
+    let directory_files = |directory: &str| {
+        let Ok(entries) = fs::read_dir(package_dir.join(directory)) else {
+            return Vec::new();
+        };
+        entries
+            .flatten()
+            .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_file()))
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect()
+    };
+    manifest_bins(&manifest, directory_files)
+        .into_iter()
+        .map(|(command, path)| (command, package_dir.join(path)))
+        .collect()
+}
+
+// The commands a manifest declares with the path of their file inside the
+// package: either a `bin` string named after the package, a `bin` object of
+// command names to files, or every file in `directories.bin`, which
+// `directory_files` lists the names of. Paths are `/`-separated without `.`
+// segments, and neither commands nor files may point outside of where they
+// belong.
+pub fn manifest_bins(manifest: &Value, directory_files: impl FnOnce(&str) -> Vec<String>) -> Vec<(String, String)> {
+    let mut bins = Vec::new();
+    match &manifest["bin"] {
+        Value::String(path) => {
+            if let Some(name) = manifest["name"].as_str() {
+                // `@scope/name` installs the command `name`
+                let command = name.rsplit('/').next().unwrap_or(name);
+                bins.push((command.to_string(), path.clone()));
+            }
+        }
+        Value::Object(entries) => {
+            for (command, path) in entries {
+                if let Some(path) = path.as_str() {
+                    bins.push((command.clone(), path.to_string()));
+                }
+            }
+        }
+        _ => {
+            if let Some(directory) = manifest["directories"]["bin"].as_str().and_then(normalize_bin_path) {
+                for file_name in directory_files(&directory) {
+                    if !file_name.starts_with('.') {
+                        bins.push((file_name.clone(), format!("{}/{}", directory, file_name)));
+                    }
+                }
+            }
+        }
+    }
+
+    bins.into_iter()
+        .filter(|(command, _)| is_plain_name(command))
+        .filter_map(|(command, path)| Some((command, normalize_bin_path(&path)?)))
+        .collect()
+}
+
+// None for paths leading out of the package
+fn normalize_bin_path(path: &str) -> Option<String> {
+    let segments: Vec<&str> = path
+        .split(['/', '\\'])
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect();
+    let inside = !segments.is_empty() && segments.iter().all(|segment| *segment != ".." && !segment.contains(':'));
+    inside.then(|| segments.join("/"))
+}
+
+fn is_plain_name(command: &str) -> bool {
+    !command.is_empty()
+        && command != "."
+        && command != ".."
+        && !command.contains(['/', '\\', ':'])
+}
+
+// Drops links whose package is gone
+fn remove_dangling(bin_dir: &Path) -> io::Result<()> {
+    let entries = match fs::read_dir(bin_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let is_link = fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if is_link && !path.exists() {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+// Relative symlink to the file, which has to be executable for the shell to
+// run it through its shebang. The store marks commands executable, but
+// packages stored before it did may still have them hardlinked from a file
+// other packages share, so those get their own copy instead of a chmod.
+#[cfg(unix)]
+fn link_bin(target: &Path, link: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(target)?.permissions().mode();
+    if mode & 0o111 != 0o111 {
+        let contents = fs::read(target)?;
+        fs::remove_file(target)?;
+        fs::write(target, contents)?;
+        fs::set_permissions(target, fs::Permissions::from_mode(mode | 0o111))?;
+    }
+    remove_link(link)?;
+    let parent = link.parent().unwrap_or(Path::new("."));
+    std::os::unix::fs::symlink(relative_path(target, parent), link)
+}
+
+// Commands can contain dots, so the extension is appended rather than replaced
+#[cfg(windows)]
+fn with_suffix(link: &Path, suffix: &str) -> PathBuf {
+    let mut path = link.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// Windows can't run scripts through their shebang, so every command gets a
+// .cmd, a PowerShell and a sh shim calling the interpreter the shebang names
+#[cfg(windows)]
+fn link_bin(target: &Path, link: &Path) -> io::Result<()> {
+    let parent = link.parent().unwrap_or(Path::new("."));
+    let relative = relative_path(target, parent);
+    let relative_windows = relative.to_string_lossy().replace('/', "\\");
+    let relative_unix = relative.to_string_lossy().replace('\\', "/");
+    let (program, args) = shebang(target).unwrap_or_default();
+
+    let cmd = if program.is_empty() {
+        format!("@ECHO off\r\n\"%~dp0\\{}\" %*\r\n", relative_windows)
+    } else {
+        format!("@ECHO off\r\n\"{}\" {} \"%~dp0\\{}\" %*\r\n", program, args, relative_windows)
+    };
+    let ps1 = if program.is_empty() {
+        format!(
+            "$basedir=Split-Path $MyInvocation.MyCommand.Definition -Parent\n& \"$basedir/{}\" $args\nexit $LASTEXITCODE\n",
+            relative_unix
+        )
+    } else {
+        format!(
+            "$basedir=Split-Path $MyInvocation.MyCommand.Definition -Parent\n& \"{}\" {} \"$basedir/{}\" $args\nexit $LASTEXITCODE\n",
+            program, args, relative_unix
+        )
+    };
+    let sh = format!(
+        "#!/bin/sh\nbasedir=$(dirname \"$(echo \"$0\" | sed -e 's,\\\\,/,g')\")\nexec {} {} \"$basedir/{}\" \"$@\"\n",
+        program, args, relative_unix
+    );
+
+    fs::write(with_suffix(link, ".cmd"), cmd)?;
+    fs::write(with_suffix(link, ".ps1"), ps1)?;
+    fs::write(link, sh)
+}
+
+// The interpreter and its arguments from a `#!` line, `/usr/bin/env node`
+// and `/usr/bin/node` both giving `node`
+#[cfg(windows)]
+fn shebang(target: &Path) -> Option<(String, String)> {
+    use std::io::BufRead;
+    let mut first_line = String::new();
+    io::BufReader::new(fs::File::open(target).ok()?).read_line(&mut first_line).ok()?;
+    let mut words = first_line.strip_prefix("#!")?.split_whitespace();
+    let mut program = words.next()?;
+    if program == "env" || program.ends_with("/env") {
+        program = words.find(|word| !word.starts_with('-'))?;
+    }
+    let program = program.rsplit('/').next().unwrap_or(program);
+    Some((program.to_string(), words.collect::<Vec<_>>().join(" ")))
+}
@@ -0,0 +1,135 @@
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::lockfile::Lockfile;
+
+// Records the dependencies that were installed without running their install
+// scripts, so `qnpm approve-builds` can ask about them later
+const PENDING_BUILDS_FILE: &str = ".qnpm-pending-builds.json";
+
+// Which dependencies may run install scripts. Nothing may unless it's listed
+// under `qnpm.onlyBuiltDependencies` in package.json or in
+// `only_built_dependencies` of the config. Dependencies listed under
+// `qnpm.ignoredBuiltDependencies` are never built and not reported either.
+pub struct BuildPolicy {
+    allowed: BTreeSet<String>,
+    ignored: BTreeSet<String>,
+}
+
+impl BuildPolicy {
+    pub fn load(config: &Config, current_dir: &Path) -> Self {
+        let manifest: Value = fs::read(current_dir.join("package.json"))
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default();
+        let names = |field: &str| -> BTreeSet<String> {
+            manifest["qnpm"][field]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|name| name.as_str().map(str::to_string))
+                .collect()
+        };
+        let mut allowed = names("onlyBuiltDependencies");
+        allowed.extend(config.only_built_dependencies.iter().cloned());
+        Self {
+            allowed,
+            ignored: names("ignoredBuiltDependencies"),
+        }
+    }
+
+    pub fn allows(&self, package_name: &str) -> bool {
+        self.allowed.contains(package_name)
+    }
+
+    pub fn ignores(&self, package_name: &str) -> bool {
+        !self.allows(package_name) && self.ignored.contains(package_name)
+    }
+
+    // Adds packages to the lists in package.json
+    pub fn record(current_dir: &Path, allowed: &[String], ignored: &[String]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let package_json = current_dir.join("package.json");
+        let mut manifest: Value = serde_json::from_str(&fs::read_to_string(&package_json)?)?;
+        if !manifest["qnpm"].is_object() {
+            manifest["qnpm"] = json!({});
+        }
+        for (field, names) in [("onlyBuiltDependencies", allowed), ("ignoredBuiltDependencies", ignored)] {
+            if names.is_empty() {
+                continue;
+            }
+            let mut list: BTreeSet<String> = manifest["qnpm"][field]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|name| name.as_str().map(str::to_string))
+                .collect();
+            list.extend(names.iter().cloned());
+            manifest["qnpm"][field] = json!(list);
+        }
+        fs::write(&package_json, serde_json::to_string_pretty(&manifest)?)?;
+        Ok(())
+    }
+}
+
+// Package keys to the directories, relative to the project, of the copies
+// whose scripts were blocked
+#[derive(Default)]
+pub struct PendingBuilds {
+    packages: BTreeMap<String, BTreeSet<PathBuf>>,
+}
+
+impl PendingBuilds {
+    pub fn load(current_dir: &Path) -> Self {
+        let packages = fs::read(current_dir.join("node_modules").join(PENDING_BUILDS_FILE))
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default();
+        Self { packages }
+    }
+
+    pub fn save(&self, current_dir: &Path) -> io::Result<()> {
+        let path = current_dir.join("node_modules").join(PENDING_BUILDS_FILE);
+        if self.packages.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let contents = serde_json::to_vec_pretty(&self.packages).map_err(io::Error::other)?;
+        fs::write(path, contents)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.packages.keys()
+    }
+
+    pub fn add(&mut self, key: &str, relative_dir: PathBuf) {
+        self.packages.entry(key.to_string()).or_default().insert(relative_dir);
+    }
+
+    // Forgets packages that are no longer installed or that the policy has
+    // decided on since, returning the directories of the ones now allowed
+    pub fn take_decided(&mut self, lockfile: &Lockfile, policy: &BuildPolicy) -> BTreeMap<String, BTreeSet<PathBuf>> {
+        let mut allowed = BTreeMap::new();
+        self.packages.retain(|key, dirs| {
+            let Some(package) = lockfile.packages.get(key) else {
+                return false;
+            };
+            if policy.allows(&package.name) {
+                allowed.insert(key.clone(), std::mem::take(dirs));
+                return false;
+            }
+            !policy.ignores(&package.name)
+        });
+        allowed
+    }
+}
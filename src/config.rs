@@ -3,15 +3,167 @@
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
+pub const DEFAULT_REGISTRY: &str = "https://registry.npmjs.org/";
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub cache_dir: PathBuf,
+    #[serde(default = "default_registry")]
+    pub registry: String,
+    // Maximum number of registry requests in flight at once
+    #[serde(default = "default_network_concurrency")]
+    pub network_concurrency: usize,
+    // Seconds before a registry request is given up on
+    #[serde(default = "default_fetch_timeout")]
+    pub fetch_timeout: u64,
+    // Seconds a cached packument is used without asking the registry
+    #[serde(default = "default_metadata_ttl")]
+    pub metadata_ttl: u64,
+    // How packages get from the store into node_modules
+    #[serde(default)]
+    pub package_import_method: ImportMethod,
+    // How packages are arranged inside node_modules
+    #[serde(default)]
+    pub node_linker: NodeLinker,
+    // Skips the install scripts of dependencies and the project
+    #[serde(default)]
+    pub ignore_scripts: bool,
+    // Dependencies allowed to run install scripts, on top of the ones listed
+    // in package.json
+    #[serde(default)]
+    pub only_built_dependencies: Vec<String>,
+    // Leaves devDependencies out of installs, like npm's --omit=dev
+    #[serde(default)]
+    pub production: bool,
+    // Shell running scripts instead of `sh` (`cmd` on Windows)
+    #[serde(default)]
+    pub script_shell: Option<String>,
+    // Maximum number of workspace members running a script at once with
+    // `qnpm run --recursive` or `--filter`
+    #[serde(default = "default_workspace_concurrency")]
+    pub workspace_concurrency: usize,
+    // Chosen per run with --offline/--prefer-offline, never saved
+    #[serde(skip)]
+    pub network_mode: NetworkMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMethod {
+    // Reflink, then hardlink, then copy, whichever works first
+    #[default]
+    Auto,
+    Hardlink,
+    // Falls back to copying where the filesystem can't reflink
+    Reflink,
+    Copy,
+}
+
+impl ImportMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportMethod::Auto => "auto",
+            ImportMethod::Hardlink => "hardlink",
+            ImportMethod::Reflink => "reflink",
+            ImportMethod::Copy => "copy",
+        }
+    }
+}
+
+impl std::str::FromStr for ImportMethod {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "auto" => Ok(ImportMethod::Auto),
+            "hardlink" => Ok(ImportMethod::Hardlink),
+            "reflink" | "clone" => Ok(ImportMethod::Reflink),
+            "copy" => Ok(ImportMethod::Copy),
+            _ => Err(format!("Unknown package import method '{}', expected auto, hardlink, reflink or copy", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeLinker {
+    // npm's layout, one version of every package at the top of node_modules
+    // and conflicting versions nested under the packages needing them
+    #[default]
+    #[serde(alias = "flat")]
+    Hoisted,
+    // Packages in `node_modules/.qnpm`, each only seeing its own dependencies
+    Isolated,
+}
+
+impl NodeLinker {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NodeLinker::Hoisted => "hoisted",
+            NodeLinker::Isolated => "isolated",
+        }
+    }
+}
+
+impl std::str::FromStr for NodeLinker {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "hoisted" | "flat" => Ok(NodeLinker::Hoisted),
+            "isolated" => Ok(NodeLinker::Isolated),
+            _ => Err(format!("Unknown node linker '{}', expected hoisted or isolated", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NetworkMode {
+    #[default]
+    Online,
+    // Cached packuments are used regardless of their age as long as they
+    // have a version satisfying the range
+    PreferOffline,
+    // Nothing is requested, everything has to come from the cache
+    Offline,
+}
+
+fn default_registry() -> String {
+    DEFAULT_REGISTRY.to_string()
+}
+
+fn default_network_concurrency() -> usize {
+    16
+}
+
+fn default_fetch_timeout() -> u64 {
+    60
+}
+
+fn default_metadata_ttl() -> u64 {
+    300
+}
+
+fn default_workspace_concurrency() -> usize {
+    4
 }
 
 impl Config {
     pub fn new() -> Self {
         Self {
             cache_dir: dirs::home_dir().unwrap_or_else(|| PathBuf::from(".")),
+            registry: default_registry(),
+            network_concurrency: default_network_concurrency(),
+            fetch_timeout: default_fetch_timeout(),
+            metadata_ttl: default_metadata_ttl(),
+            package_import_method: ImportMethod::default(),
+            node_linker: NodeLinker::default(),
+            ignore_scripts: false,
+            only_built_dependencies: Vec::new(),
+            production: false,
+            script_shell: None,
+            workspace_concurrency: default_workspace_concurrency(),
+            network_mode: NetworkMode::default(),
         }
     }
 
@@ -31,4 +183,64 @@ impl Config {
         std::fs::write(config_path, config_str)?;
         Ok(())
     }
+
+    // Environment variables override the config file, QNPM_* first and then
+    // the npm_config_* names npm itself understands
+    pub fn apply_env(&mut self) {
+        if let Some(registry) = env_var("QNPM_REGISTRY").or_else(|| env_var("npm_config_registry")) {
+            self.registry = registry;
+        }
+        let network_concurrency = env_var("QNPM_NETWORK_CONCURRENCY").or_else(|| env_var("npm_config_network_concurrency"));
+        if let Some(network_concurrency) = network_concurrency.and_then(|value| value.parse().ok()) {
+            self.network_concurrency = network_concurrency;
+        }
+        let fetch_timeout = env_var("QNPM_FETCH_TIMEOUT").or_else(|| env_var("npm_config_fetch_timeout"));
+        if let Some(fetch_timeout) = fetch_timeout.and_then(|value| value.parse().ok()) {
+            self.fetch_timeout = fetch_timeout;
+        }
+        if let Some(metadata_ttl) = env_var("QNPM_METADATA_TTL").and_then(|value| value.parse().ok()) {
+            self.metadata_ttl = metadata_ttl;
+        }
+        let import_method = env_var("QNPM_PACKAGE_IMPORT_METHOD").or_else(|| env_var("npm_config_package_import_method"));
+        if let Some(import_method) = import_method.and_then(|value| value.parse().ok()) {
+            self.package_import_method = import_method;
+        }
+        let node_linker = env_var("QNPM_NODE_LINKER").or_else(|| env_var("npm_config_node_linker"));
+        if let Some(node_linker) = node_linker.and_then(|value| value.parse().ok()) {
+            self.node_linker = node_linker;
+        }
+        if let Some(script_shell) = env_var("QNPM_SCRIPT_SHELL").or_else(|| env_var("npm_config_script_shell")) {
+            self.script_shell = Some(script_shell);
+        }
+        let workspace_concurrency =
+            env_var("QNPM_WORKSPACE_CONCURRENCY").or_else(|| env_var("npm_config_workspace_concurrency"));
+        if let Some(workspace_concurrency) = workspace_concurrency.and_then(|value| value.parse().ok()) {
+            self.workspace_concurrency = workspace_concurrency;
+        }
+        if env_flag("QNPM_IGNORE_SCRIPTS") || env_flag("npm_config_ignore_scripts") {
+            self.ignore_scripts = true;
+        }
+        let omit = env_var("npm_config_omit").unwrap_or_default();
+        if env_flag("QNPM_PRODUCTION")
+            || env_flag("npm_config_production")
+            || omit.split([' ', ',']).any(|omitted| omitted == "dev")
+            || env_var("NODE_ENV").is_some_and(|node_env| node_env == "production")
+        {
+            self.production = true;
+        }
+        if env_flag("QNPM_PREFER_OFFLINE") || env_flag("npm_config_prefer_offline") {
+            self.network_mode = NetworkMode::PreferOffline;
+        }
+        if env_flag("QNPM_OFFLINE") || env_flag("npm_config_offline") {
+            self.network_mode = NetworkMode::Offline;
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_flag(name: &str) -> bool {
+    env_var(name).is_some_and(|value| value == "true" || value == "1")
 }
@@ -0,0 +1,133 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha512};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::add::{self, package_path, parse_package_spec};
+use crate::bin_links::package_bins;
+use crate::config::{Config, NetworkMode};
+use crate::lockfile::Lockfile;
+use crate::registry::Registry;
+use crate::run::{bin_path, exit_code, quote_args, script_command};
+use crate::store::Store;
+
+#[derive(Debug, Error)]
+pub enum ExecError {
+    #[error("Command not found: {0}")]
+    CommandNotFound(String),
+    #[error("{0} doesn't provide any commands")]
+    NoBins(String),
+    #[error("{package} provides several commands, none named {name}: {commands}")]
+    AmbiguousBin {
+        package: String,
+        name: String,
+        commands: String,
+    },
+}
+
+// Runs a command from node_modules/.bin of the project or a directory above
+// it, or from PATH, the way npm runs scripts. Returns its exit code.
+pub fn exec(current_dir: &Path, command: &str, args: &[String], config: &Config) -> Result<i32, Box<dyn Error + Send + Sync>> {
+    let path = bin_path(current_dir);
+    if find_command(command, &path).is_none() {
+        return Err(ExecError::CommandNotFound(command.to_string()).into());
+    }
+    run_command(current_dir, command, args, path, config)
+}
+
+// Installs a package into its own directory in the cache, without touching the
+// project, and runs its command with the given arguments. The install is kept
+// and reused until packuments would be fetched again.
+pub async fn dlx(
+    current_dir: &Path,
+    spec: &str,
+    args: &[String],
+    store: Arc<Store>,
+    registry: Arc<Registry>,
+    config: &Config,
+) -> Result<i32, Box<dyn Error + Send + Sync>> {
+    let (name, range) = parse_package_spec(spec);
+    let dlx_dir = config.cache_dir.join("dlx").join(dlx_dir_name(spec));
+    fs::create_dir_all(dlx_dir.join("node_modules"))?;
+    // Running the package runs its code anyway, so it may build itself too.
+    // Its dependencies still need to be allowed in the config.
+    let manifest = json!({
+        "dependencies": { name: range.unwrap_or("latest") },
+        "qnpm": { "onlyBuiltDependencies": [name] },
+    });
+    fs::write(dlx_dir.join("package.json"), serde_json::to_string_pretty(&manifest)?)?;
+    if config.network_mode == NetworkMode::Online && is_stale(&Lockfile::path(&dlx_dir), config.metadata_ttl) {
+        fs::remove_file(Lockfile::path(&dlx_dir))?;
+    }
+
+    add::install_project(Arc::new(dlx_dir.clone()), store, registry, config).await?;
+
+    let command = package_command(&package_path(&dlx_dir.join("node_modules"), name), name)?;
+    // The package's commands come first, then the project's as usual
+    let mut paths = vec![dlx_dir.join("node_modules").join(".bin")];
+    paths.extend(std::env::split_paths(&bin_path(current_dir)));
+    run_command(current_dir, &command, args, std::env::join_paths(paths)?, config)
+}
+
+fn run_command(
+    current_dir: &Path,
+    command: &str,
+    args: &[String],
+    path: std::ffi::OsString,
+    config: &Config,
+) -> Result<i32, Box<dyn Error + Send + Sync>> {
+    let manifest: Value = fs::read(current_dir.join("package.json"))
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default();
+    let mut script = quote_args(&[command.to_string()]);
+    if !args.is_empty() {
+        script = format!("{} {}", script, quote_args(args));
+    }
+    let status = script_command(&script, "npx", &manifest, current_dir, current_dir, config)
+        .env("PATH", path)
+        .status()?;
+    Ok(exit_code(status))
+}
+
+// The command to run from a package, the only one it has or the one named
+// after it, like npx picks
+fn package_command(package_dir: &Path, package_name: &str) -> Result<String, ExecError> {
+    let commands: Vec<String> = package_bins(package_dir).into_iter().map(|(command, _)| command).collect();
+    let unscoped = package_name.rsplit('/').next().unwrap_or(package_name);
+    match commands.as_slice() {
+        [] => Err(ExecError::NoBins(package_name.to_string())),
+        [command] => Ok(command.clone()),
+        _ if commands.iter().any(|command| command == unscoped) => Ok(unscoped.to_string()),
+        _ => Err(ExecError::AmbiguousBin {
+            package: package_name.to_string(),
+            name: unscoped.to_string(),
+            commands: commands.join(", "),
+        }),
+    }
+}
+
+// Specs can contain characters that aren't allowed in file names
+fn dlx_dir_name(spec: &str) -> String {
+    Sha512::digest(spec.as_bytes())[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn is_stale(path: &Path, ttl: u64) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified.elapsed().unwrap_or_default() > Duration::from_secs(ttl))
+}
+
+fn find_command(command: &str, path: &std::ffi::OsStr) -> Option<PathBuf> {
+    let extensions: &[&str] = if cfg!(windows) { &["", ".cmd", ".exe", ".bat"] } else { &[""] };
+    std::env::split_paths(path).find_map(|dir| {
+        extensions
+            .iter()
+            .map(|extension| dir.join(format!("{}{}", command, extension)))
+            .find(|candidate| candidate.is_file())
+    })
+}
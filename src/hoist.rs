@@ -0,0 +1,467 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use crate::lockfile::{package_key, Lockfile};
+use crate::semver::Version;
+
+// Where one copy of a package goes in a hoisted node_modules tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    // Names of the packages whose node_modules this copy is nested in,
+    // outermost first. Empty for the project's own node_modules.
+    pub parents: Vec<String>,
+    pub name: String,
+    pub version: String,
+}
+
+// Marks the node of a workspace member, followed by the member's path
+const LINK_PREFIX: &str = "link:";
+
+struct Node {
+    name: String,
+    version: String,
+    parent: Option<usize>,
+    // The contents of this node's node_modules
+    children: BTreeMap<String, usize>,
+}
+
+// Lays out a lockfile the way npm does. Every package name gets one version at
+// the top of node_modules: the one the project depends on, otherwise the one
+// most packages depend on (the highest on a tie). Packages needing a
+// different version than what Node would find walking up from them get their
+// own copy nested in their node_modules.
+//
+// Workspace members sit at the top as well, linked rather than placed, so
+// their conflicting dependencies end up in the member's own node_modules.
+//
+// Placements come out parents first, so they can be created in order.
+pub fn hoist(lockfile: &Lockfile) -> Vec<Placement> {
+    let mut nodes = vec![Node {
+        name: String::new(),
+        version: String::new(),
+        parent: None,
+        children: BTreeMap::new(),
+    }];
+    let mut queue = VecDeque::new();
+    for (name, version) in top_level_versions(lockfile) {
+        let id = add_node(&mut nodes, 0, &name, &version);
+        queue.push_back(id);
+    }
+
+    while let Some(id) = queue.pop_front() {
+        let dependencies = match nodes[id].version.strip_prefix(LINK_PREFIX) {
+            Some(path) => lockfile.workspaces.get(path).map(|importer| &importer.dependencies),
+            None => lockfile
+                .packages
+                .get(&package_key(&nodes[id].name, &nodes[id].version))
+                .map(|package| &package.dependencies),
+        };
+        let Some(dependencies) = dependencies else {
+            continue;
+        };
+        for (name, version) in dependencies {
+            let found = resolve(&nodes, id, name);
+            if found.is_some_and(|found| &nodes[found].version == version) {
+                continue;
+            }
+            // A cycle back to a version that is shadowed further down would
+            // otherwise nest forever
+            if ancestors(&nodes, id).any(|ancestor| &nodes[ancestor].name == name && &nodes[ancestor].version == version) {
+                continue;
+            }
+            let nested = add_node(&mut nodes, id, name, version);
+            queue.push_back(nested);
+        }
+    }
+
+    let mut placements = Vec::new();
+    let mut queue: VecDeque<usize> = nodes[0].children.values().copied().collect();
+    while let Some(id) = queue.pop_front() {
+        queue.extend(nodes[id].children.values().copied());
+        if nodes[id].version.starts_with(LINK_PREFIX) {
+            continue;
+        }
+        let mut parents: Vec<String> = ancestors(&nodes, id)
+            .skip(1)
+            .filter(|&ancestor| ancestor != 0)
+            .map(|ancestor| nodes[ancestor].name.clone())
+            .collect();
+        parents.reverse();
+        placements.push(Placement {
+            parents,
+            name: nodes[id].name.clone(),
+            version: nodes[id].version.clone(),
+        });
+    }
+    placements
+}
+
+fn add_node(nodes: &mut Vec<Node>, parent: usize, name: &str, version: &str) -> usize {
+    let id = nodes.len();
+    nodes.push(Node {
+        name: name.to_string(),
+        version: version.to_string(),
+        parent: Some(parent),
+        children: BTreeMap::new(),
+    });
+    nodes[parent].children.insert(name.to_string(), id);
+    id
+}
+
+// The node itself, then its parents up to the project
+fn ancestors(nodes: &[Node], id: usize) -> impl Iterator<Item = usize> + '_ {
+    std::iter::successors(Some(id), |&id| nodes[id].parent)
+}
+
+// What `require(name)` finds from inside a node: its own node_modules, then
+// the node_modules of every directory above it
+fn resolve(nodes: &[Node], from: usize, name: &str) -> Option<usize> {
+    ancestors(nodes, from).find_map(|id| nodes[id].children.get(name).copied())
+}
+
+fn top_level_versions(lockfile: &Lockfile) -> BTreeMap<String, String> {
+    let mut dependents: HashMap<(&str, &str), usize> = HashMap::new();
+    let workspace_dependencies = lockfile.workspaces.values().map(|importer| &importer.dependencies);
+    for dependencies in lockfile.packages.values().map(|package| &package.dependencies).chain(workspace_dependencies) {
+        for (name, version) in dependencies {
+            *dependents.entry((name.as_str(), version.as_str())).or_default() += 1;
+        }
+    }
+
+    let count = |name: &str, version: &str| dependents.get(&(name, version)).copied().unwrap_or(0);
+
+    let mut versions: BTreeMap<String, String> = BTreeMap::new();
+    for package in lockfile.packages.values() {
+        let replace = match versions.get(&package.name) {
+            Some(current) => more_common(
+                (count(&package.name, &package.version), &package.version),
+                (count(&package.name, current), current),
+            ),
+            None => true,
+        };
+        if replace {
+            versions.insert(package.name.clone(), package.version.clone());
+        }
+    }
+    for (name, version) in &lockfile.root.dependencies {
+        versions.insert(name.clone(), version.clone());
+    }
+    for (path, importer) in &lockfile.workspaces {
+        if let Some(name) = &importer.name {
+            versions.insert(name.clone(), format!("{}{}", LINK_PREFIX, path));
+        }
+    }
+    versions
+}
+
+fn more_common(candidate: (usize, &String), current: (usize, &String)) -> bool {
+    if candidate.0 != current.0 {
+        return candidate.0 > current.0;
+    }
+    match (Version::parse(candidate.1), Version::parse(current.1)) {
+        (Ok(candidate), Ok(current)) => candidate > current,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lockfile::{DependencySpecs, Importer, LockedPackage};
+    use std::collections::BTreeSet;
+
+    // Builds a lockfile from `name@version -> [name@version, ..]` edges, with
+    // `roots` as the project's dependencies
+    fn lockfile(roots: &[&str], packages: &[(&str, &[&str])]) -> Lockfile {
+        let split = |key: &str| {
+            let (name, version) = key.rsplit_once('@').unwrap();
+            (name.to_string(), version.to_string())
+        };
+        let mut lockfile = Lockfile::new(DependencySpecs::default());
+        for root in roots {
+            let (name, version) = split(root);
+            lockfile.root.specs.specifiers.insert(name.clone(), version.clone());
+            lockfile.root.dependencies.insert(name, version);
+        }
+        for (key, dependencies) in packages {
+            let (name, version) = split(key);
+            lockfile.packages.insert(key.to_string(), LockedPackage {
+                name,
+                version,
+                resolved: String::new(),
+                integrity: None,
+                dependencies: dependencies.iter().map(|dependency| split(dependency)).collect(),
+                optional_dependencies: BTreeSet::new(),
+                dev: false,
+                optional: false,
+            });
+        }
+        lockfile
+    }
+
+    // Placements as `a/node_modules/b@1.0.0` strings, sorted
+    fn layout(lockfile: &Lockfile) -> Vec<String> {
+        let mut layout: Vec<String> = hoist(lockfile)
+            .into_iter()
+            .map(|placement| {
+                let mut path = placement.parents;
+                path.push(format!("{}@{}", placement.name, placement.version));
+                path.join("/node_modules/")
+            })
+            .collect();
+        layout.sort();
+        layout
+    }
+
+    // Checks that every placed package finds exactly the versions it locked
+    // when resolving the way Node does
+    fn assert_resolvable(lockfile: &Lockfile) {
+        let placements = hoist(lockfile);
+        let installed: HashMap<Vec<String>, &str> = placements
+            .iter()
+            .map(|placement| {
+                let mut path = placement.parents.clone();
+                path.push(placement.name.clone());
+                (path, placement.version.as_str())
+            })
+            .collect();
+        for placement in &placements {
+            let package = &lockfile.packages[&package_key(&placement.name, &placement.version)];
+            let mut own_path = placement.parents.clone();
+            own_path.push(placement.name.clone());
+            for (name, version) in &package.dependencies {
+                let found = (0..=own_path.len()).rev().find_map(|depth| {
+                    let mut path = own_path[..depth].to_vec();
+                    path.push(name.clone());
+                    installed.get(&path).copied()
+                });
+                assert_eq!(
+                    found,
+                    Some(version.as_str()),
+                    "{}@{} at {:?} resolves {} to {:?}",
+                    placement.name,
+                    placement.version,
+                    placement.parents,
+                    name,
+                    found
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn hoists_everything_without_conflicts() {
+        let lockfile = lockfile(&["a@1.0.0"], &[
+            ("a@1.0.0", &["b@1.0.0"]),
+            ("b@1.0.0", &["c@1.0.0"]),
+            ("c@1.0.0", &[]),
+        ]);
+        assert_eq!(layout(&lockfile), ["a@1.0.0", "b@1.0.0", "c@1.0.0"]);
+        assert_resolvable(&lockfile);
+    }
+
+    #[test]
+    fn nests_conflicting_version_under_dependent() {
+        let lockfile = lockfile(&["a@1.0.0", "b@2.0.0"], &[
+            ("a@1.0.0", &["b@1.0.0"]),
+            ("b@1.0.0", &[]),
+            ("b@2.0.0", &[]),
+        ]);
+        assert_eq!(layout(&lockfile), ["a/node_modules/b@1.0.0", "a@1.0.0", "b@2.0.0"]);
+        assert_resolvable(&lockfile);
+    }
+
+    #[test]
+    fn most_common_version_goes_to_the_top() {
+        let lockfile = lockfile(&["a@1.0.0", "b@1.0.0", "c@1.0.0"], &[
+            ("a@1.0.0", &["d@1.0.0"]),
+            ("b@1.0.0", &["d@2.0.0"]),
+            ("c@1.0.0", &["d@2.0.0"]),
+            ("d@1.0.0", &[]),
+            ("d@2.0.0", &[]),
+        ]);
+        assert_eq!(
+            layout(&lockfile),
+            ["a/node_modules/d@1.0.0", "a@1.0.0", "b@1.0.0", "c@1.0.0", "d@2.0.0"]
+        );
+        assert_resolvable(&lockfile);
+    }
+
+    #[test]
+    fn highest_version_wins_a_tie() {
+        let lockfile = lockfile(&["a@1.0.0", "b@1.0.0"], &[
+            ("a@1.0.0", &["d@1.0.0"]),
+            ("b@1.0.0", &["d@1.10.0"]),
+            ("d@1.0.0", &[]),
+            ("d@1.10.0", &[]),
+        ]);
+        assert_eq!(
+            layout(&lockfile),
+            ["a/node_modules/d@1.0.0", "a@1.0.0", "b@1.0.0", "d@1.10.0"]
+        );
+        assert_resolvable(&lockfile);
+    }
+
+    #[test]
+    fn project_dependency_beats_most_common_version() {
+        let lockfile = lockfile(&["a@1.0.0", "b@1.0.0", "d@1.0.0"], &[
+            ("a@1.0.0", &["d@2.0.0"]),
+            ("b@1.0.0", &["d@2.0.0"]),
+            ("d@1.0.0", &[]),
+            ("d@2.0.0", &[]),
+        ]);
+        assert_eq!(
+            layout(&lockfile),
+            [
+                "a/node_modules/d@2.0.0",
+                "a@1.0.0",
+                "b/node_modules/d@2.0.0",
+                "b@1.0.0",
+                "d@1.0.0",
+            ]
+        );
+        assert_resolvable(&lockfile);
+    }
+
+    #[test]
+    fn nested_copy_brings_its_own_conflicting_dependencies() {
+        // b@2 is nested under a and needs c@2, while c@1 is at the top
+        let lockfile = lockfile(&["a@1.0.0", "b@1.0.0", "c@1.0.0"], &[
+            ("a@1.0.0", &["b@2.0.0"]),
+            ("b@1.0.0", &[]),
+            ("b@2.0.0", &["c@2.0.0"]),
+            ("c@1.0.0", &[]),
+            ("c@2.0.0", &[]),
+        ]);
+        assert_eq!(
+            layout(&lockfile),
+            [
+                "a/node_modules/b/node_modules/c@2.0.0",
+                "a/node_modules/b@2.0.0",
+                "a@1.0.0",
+                "b@1.0.0",
+                "c@1.0.0",
+            ]
+        );
+        assert_resolvable(&lockfile);
+    }
+
+    #[test]
+    fn nested_copy_shadowing_the_top_level_version_for_its_subtree() {
+        // x nests d@2, but x's other dependency y (hoisted to the top) still
+        // sees the top-level d@1 it wants
+        let lockfile = lockfile(&["x@1.0.0", "d@1.0.0"], &[
+            ("x@1.0.0", &["d@2.0.0", "y@1.0.0"]),
+            ("y@1.0.0", &["d@1.0.0"]),
+            ("d@1.0.0", &[]),
+            ("d@2.0.0", &["e@1.0.0"]),
+            ("e@1.0.0", &["d@2.0.0"]),
+        ]);
+        assert_eq!(
+            layout(&lockfile),
+            [
+                "d@1.0.0",
+                "e/node_modules/d@2.0.0",
+                "e@1.0.0",
+                "x/node_modules/d@2.0.0",
+                "x@1.0.0",
+                "y@1.0.0",
+            ]
+        );
+        assert_resolvable(&lockfile);
+    }
+
+    #[test]
+    fn cycles_terminate() {
+        let lockfile = lockfile(&["a@1.0.0"], &[
+            ("a@1.0.0", &["b@1.0.0"]),
+            ("b@1.0.0", &["a@1.0.0"]),
+        ]);
+        assert_eq!(layout(&lockfile), ["a@1.0.0", "b@1.0.0"]);
+        assert_resolvable(&lockfile);
+    }
+
+    #[test]
+    fn conflicting_cycles_terminate() {
+        // a@2 and b@2 need each other but the top level holds a@1 and b@1
+        let lockfile = lockfile(&["a@1.0.0", "b@1.0.0", "c@1.0.0"], &[
+            ("a@1.0.0", &[]),
+            ("b@1.0.0", &[]),
+            ("c@1.0.0", &["a@2.0.0"]),
+            ("a@2.0.0", &["b@2.0.0"]),
+            ("b@2.0.0", &["a@2.0.0"]),
+        ]);
+        assert_eq!(
+            layout(&lockfile),
+            [
+                "a@1.0.0",
+                "b@1.0.0",
+                "c/node_modules/a/node_modules/b@2.0.0",
+                "c/node_modules/a@2.0.0",
+                "c@1.0.0",
+            ]
+        );
+        assert_resolvable(&lockfile);
+    }
+
+    #[test]
+    fn scoped_packages_conflict_like_any_other() {
+        let lockfile = lockfile(&["@scope/a@1.0.0", "@scope/b@2.0.0"], &[
+            ("@scope/a@1.0.0", &["@scope/b@1.0.0"]),
+            ("@scope/b@1.0.0", &[]),
+            ("@scope/b@2.0.0", &[]),
+        ]);
+        assert_eq!(
+            layout(&lockfile),
+            ["@scope/a/node_modules/@scope/b@1.0.0", "@scope/a@1.0.0", "@scope/b@2.0.0"]
+        );
+        assert_resolvable(&lockfile);
+    }
+
+    #[test]
+    fn workspace_members_get_their_conflicting_dependencies() {
+        // The member app wants d@2 while the root and most packages want d@1
+        let mut lockfile = lockfile(&["a@1.0.0", "d@1.0.0"], &[
+            ("a@1.0.0", &["d@1.0.0"]),
+            ("b@1.0.0", &["d@2.0.0"]),
+            ("d@1.0.0", &[]),
+            ("d@2.0.0", &[]),
+        ]);
+        lockfile.workspaces.insert("packages/app".to_string(), Importer {
+            name: Some("app".to_string()),
+            dependencies: [("b", "1.0.0"), ("d", "2.0.0")]
+                .into_iter()
+                .map(|(name, version)| (name.to_string(), version.to_string()))
+                .collect(),
+            ..Importer::default()
+        });
+        assert_eq!(
+            layout(&lockfile),
+            ["a@1.0.0", "app/node_modules/d@2.0.0", "b/node_modules/d@2.0.0", "b@1.0.0", "d@1.0.0"]
+        );
+    }
+
+    #[test]
+    fn parents_come_before_their_nested_packages() {
+        let lockfile = lockfile(&["a@1.0.0", "b@1.0.0", "c@1.0.0"], &[
+            ("a@1.0.0", &["b@2.0.0"]),
+            ("b@1.0.0", &[]),
+            ("b@2.0.0", &["c@2.0.0"]),
+            ("c@1.0.0", &[]),
+            ("c@2.0.0", &[]),
+        ]);
+        let placements = hoist(&lockfile);
+        for (position, placement) in placements.iter().enumerate() {
+            for depth in 0..placement.parents.len() {
+                let parent = &placement.parents[depth];
+                assert!(
+                    placements[..position]
+                        .iter()
+                        .any(|earlier| &earlier.name == parent && earlier.parents[..] == placement.parents[..depth]),
+                    "{} placed before its parent {}",
+                    placement.name,
+                    parent
+                );
+            }
+        }
+    }
+}
@@ -1,9 +1,9 @@
 use std::error::Error;
 use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::io::{self, Write};
 
-pub fn create_bare_package_json(current_dir: &PathBuf) -> Result<(), Box<dyn Error>> {
+pub fn create_bare_package_json(current_dir: &Path) -> Result<(), Box<dyn Error>> {
     let package_json_path = current_dir.join("package.json");
     let mut package_json_file = File::create(&package_json_path)?;
     package_json_file.write_all(b"{}")?;
@@ -40,7 +40,7 @@ pub fn prompt_with_default(prompt: &str, default: &str) -> String {
     }
 }
 
-pub fn initialize_node(current_dir: &PathBuf) -> Result<(), Box<dyn Error>> {
+pub fn initialize_node(current_dir: &Path) -> Result<(), Box<dyn Error>> {
     let folder_name = current_dir.file_name().unwrap().to_str().unwrap();
 
     let package_name = prompt_with_default("package name", folder_name);
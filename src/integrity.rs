@@ -0,0 +1,70 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum IntegrityError {
+    #[error("no supported hash algorithm in '{0}'")]
+    Unsupported(String),
+    #[error("expected {expected}, got {actual}")]
+    Mismatch { expected: String, actual: String },
+}
+
+// Strongest first, so a registry sending several hashes gets checked against
+// the best one we know
+const ALGORITHMS: [&str; 4] = ["sha512", "sha384", "sha256", "sha1"];
+
+fn digest(algorithm: &str, data: &[u8]) -> Option<Vec<u8>> {
+    match algorithm {
+        "sha512" => Some(Sha512::digest(data).to_vec()),
+        "sha384" => Some(Sha384::digest(data).to_vec()),
+        "sha256" => Some(Sha256::digest(data).to_vec()),
+        "sha1" => Some(Sha1::digest(data).to_vec()),
+        _ => None,
+    }
+}
+
+pub fn sri(algorithm: &str, data: &[u8]) -> Option<String> {
+    digest(algorithm, data).map(|hash| format!("{}-{}", algorithm, STANDARD.encode(hash)))
+}
+
+// Checks `data` against a Subresource Integrity string such as
+// `sha512-<base64>`. Several space separated hashes may be given, the data
+// has to match one of the hashes of the strongest supported algorithm.
+pub fn verify(data: &[u8], expected: &str) -> Result<(), IntegrityError> {
+    let entries: Vec<(&str, &str)> = expected
+        .split_whitespace()
+        .filter_map(|entry| entry.split_once('-'))
+        // Options after `?` are reserved by the SRI spec and ignored
+        .map(|(algorithm, hash)| (algorithm, hash.split('?').next().unwrap_or(hash)))
+        .collect();
+    let algorithm = ALGORITHMS
+        .iter()
+        .find(|algorithm| entries.iter().any(|(candidate, _)| candidate == *algorithm))
+        .ok_or_else(|| IntegrityError::Unsupported(expected.to_string()))?;
+
+    let actual = digest(algorithm, data).unwrap_or_default();
+    let matches = entries
+        .iter()
+        .filter(|(candidate, _)| candidate == algorithm)
+        .any(|(_, hash)| STANDARD.decode(hash).is_ok_and(|hash| hash == actual));
+    if matches {
+        Ok(())
+    } else {
+        Err(IntegrityError::Mismatch {
+            expected: expected.to_string(),
+            actual: format!("{}-{}", algorithm, STANDARD.encode(actual)),
+        })
+    }
+}
+
+// Turns the hex sha1 `shasum` older packages only publish into an SRI string
+pub fn from_shasum(shasum: &str) -> Option<String> {
+    let bytes = (0..shasum.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(shasum.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(format!("sha1-{}", STANDARD.encode(bytes)))
+}
@@ -0,0 +1,294 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::add::{package_path, remove_package_link};
+use crate::bin_links::link_bins;
+use crate::config::NodeLinker;
+use crate::link::{link_dir, remove_link, LinkError};
+use crate::lockfile::{package_key, Lockfile, ROOT_IMPORTER};
+use crate::hoist::hoist;
+use crate::store::{PackageIndex, Store};
+
+// Directory inside node_modules holding every package of the isolated layout
+const VIRTUAL_STORE_DIR: &str = ".qnpm";
+
+// A package copied into node_modules by this install, as opposed to one that
+// was already there
+pub struct ImportedPackage {
+    // `name@version`
+    pub key: String,
+    pub dir: PathBuf,
+}
+
+// Puts the locked packages into the project's node_modules, `indexes` holding
+// the store index of every package keyed by `name@version`. Returns the
+// packages that weren't installed before.
+pub fn link_packages(
+    lockfile: &Lockfile,
+    current_dir: &Path,
+    store: &Store,
+    indexes: &HashMap<String, Arc<PackageIndex>>,
+) -> Result<Vec<ImportedPackage>, Box<dyn Error + Send + Sync>> {
+    match store.node_linker {
+        NodeLinker::Hoisted => link_hoisted(lockfile, current_dir, store, indexes),
+        NodeLinker::Isolated => link_isolated(lockfile, current_dir, store, indexes),
+    }
+}
+
+// npm's layout, see `hoist`. Nested copies live inside the directory of the
+// package that needs them, so parents are imported first.
+fn link_hoisted(
+    lockfile: &Lockfile,
+    current_dir: &Path,
+    store: &Store,
+    indexes: &HashMap<String, Arc<PackageIndex>>,
+) -> Result<Vec<ImportedPackage>, Box<dyn Error + Send + Sync>> {
+    let node_modules = current_dir.join("node_modules");
+    remove_link(&node_modules.join(VIRTUAL_STORE_DIR))?;
+    let mut imported = Vec::new();
+
+    // Every node_modules directory of the tree to the packages placed in it
+    let mut contents: BTreeMap<PathBuf, BTreeSet<String>> = BTreeMap::new();
+    contents.insert(node_modules.clone(), BTreeSet::new());
+
+    // Workspace members are linked at the top, and whatever hoisting nests
+    // under them goes into their own node_modules through that link
+    for (path, importer) in &lockfile.workspaces {
+        let Some(name) = &importer.name else {
+            continue;
+        };
+        let link = package_path(&node_modules, name);
+        link_package(&current_dir.join(path), &link)?;
+        contents.entry(link.join("node_modules")).or_default();
+        contents.entry(node_modules.clone()).or_default().insert(name.clone());
+    }
+    for placement in hoist(lockfile) {
+        let parent_node_modules = placement
+            .parents
+            .iter()
+            .fold(node_modules.clone(), |dir, parent| package_path(&dir, parent).join("node_modules"));
+        let destination = package_path(&parent_node_modules, &placement.name);
+        let key = package_key(&placement.name, &placement.version);
+        let index = &indexes[&key];
+        if import_package(store, &destination, &placement.version, index)? {
+            imported.push(ImportedPackage {
+                key,
+                dir: destination.clone(),
+            });
+        }
+
+        // Dependencies bundled in the tarball stay where they are
+        let bundled = index
+            .files
+            .keys()
+            .filter_map(|path| bundled_package(path))
+            .collect();
+        contents.entry(destination.join("node_modules")).or_insert(bundled);
+        contents.entry(parent_node_modules).or_default().insert(placement.name);
+    }
+
+    // Anything else in there is left over from an earlier layout
+    for (dir, keep) in &contents {
+        prune_node_modules(dir, keep)?;
+    }
+
+    for (dir, placed) in &contents {
+        // The project's own dependencies win when two packages provide the
+        // same command
+        let mut names: Vec<String> = placed.iter().cloned().collect();
+        names.sort_by_key(|name| lockfile.root.dependencies.contains_key(name));
+        link_bins(dir, &names)?;
+    }
+    Ok(imported)
+}
+
+// The package a file bundled under `node_modules/` belongs to
+fn bundled_package(path: &str) -> Option<String> {
+    let mut segments = path.strip_prefix("node_modules/")?.split('/');
+    let first = segments.next()?;
+    if first.starts_with('@') {
+        Some(format!("{}/{}", first, segments.next()?))
+    } else {
+        Some(first.to_string())
+    }
+}
+
+// Every package version gets its own `node_modules/.qnpm/<name>@<version>/node_modules`
+// holding the package itself and links to exactly the dependencies it
+// declares. The top level only links the project's own dependencies, so
+// nothing else can be required by accident.
+fn link_isolated(
+    lockfile: &Lockfile,
+    current_dir: &Path,
+    store: &Store,
+    indexes: &HashMap<String, Arc<PackageIndex>>,
+) -> Result<Vec<ImportedPackage>, Box<dyn Error + Send + Sync>> {
+    let node_modules = current_dir.join("node_modules");
+    let virtual_store = node_modules.join(VIRTUAL_STORE_DIR);
+    for (path, importer) in lockfile.importers() {
+        let direct = importer.dependencies.keys().chain(importer.links.keys()).cloned().collect();
+        prune_node_modules(&importer_dir(current_dir, path).join("node_modules"), &direct)?;
+    }
+    prune_virtual_store(&virtual_store, lockfile)?;
+
+    let mut imported = Vec::new();
+    for (key, package) in &lockfile.packages {
+        let destination = virtual_store_path(&virtual_store, &package.name, &package.version);
+        if import_package(store, &destination, &package.version, &indexes[key])? {
+            imported.push(ImportedPackage {
+                key: key.clone(),
+                dir: destination,
+            });
+        }
+    }
+
+    // Links need their targets in place, so they're made once every package
+    // has been imported
+    for package in lockfile.packages.values() {
+        let package_node_modules = virtual_store.join(virtual_store_entry(&package.name, &package.version)).join("node_modules");
+        for (name, version) in &package.dependencies {
+            if name == &package.name {
+                continue;
+            }
+            link_package(
+                &virtual_store_path(&virtual_store, name, version),
+                &package_path(&package_node_modules, name),
+            )?;
+        }
+    }
+
+    // The project and every workspace member get their direct dependencies,
+    // linked from the virtual store or from the member they refer to
+    for (path, importer) in lockfile.importers() {
+        let importer_node_modules = importer_dir(current_dir, path).join("node_modules");
+        for (name, version) in &importer.dependencies {
+            link_package(&virtual_store_path(&virtual_store, name, version), &package_path(&importer_node_modules, name))?;
+        }
+        for (name, member_path) in &importer.links {
+            link_package(&current_dir.join(member_path), &package_path(&importer_node_modules, name))?;
+        }
+    }
+
+    // Each package sees the commands of its own dependencies, the project and
+    // the workspace members those of their direct dependencies
+    for package in lockfile.packages.values() {
+        let package_node_modules = virtual_store.join(virtual_store_entry(&package.name, &package.version)).join("node_modules");
+        let names: Vec<String> = package.dependencies.keys().filter(|name| *name != &package.name).cloned().collect();
+        link_bins(&package_node_modules, &names)?;
+    }
+    for (path, importer) in lockfile.importers() {
+        let names: Vec<String> = importer.dependencies.keys().chain(importer.links.keys()).cloned().collect();
+        link_bins(&importer_dir(current_dir, path).join("node_modules"), &names)?;
+    }
+    Ok(imported)
+}
+
+fn importer_dir(current_dir: &Path, path: &str) -> PathBuf {
+    if path == ROOT_IMPORTER {
+        current_dir.to_path_buf()
+    } else {
+        current_dir.join(path)
+    }
+}
+
+// Scoped names can't be used as a single directory name, so `@scope/name`
+// becomes `@scope+name@<version>`
+fn virtual_store_entry(package_name: &str, version: &str) -> String {
+    format!("{}@{}", package_name.replace('/', "+"), version)
+}
+
+fn virtual_store_path(virtual_store: &Path, package_name: &str, version: &str) -> PathBuf {
+    package_path(
+        &virtual_store.join(virtual_store_entry(package_name, version)).join("node_modules"),
+        package_name,
+    )
+}
+
+// Imports a package from the store, unless that version is already there.
+// Returns whether it was imported.
+fn import_package(store: &Store, destination: &Path, version: &str, index: &PackageIndex) -> std::io::Result<bool> {
+    let is_dir = fs::symlink_metadata(destination).is_ok_and(|metadata| metadata.is_dir());
+    if is_dir && installed_version(destination).as_deref() == Some(version) {
+        return Ok(false);
+    }
+    store.import(index, destination)?;
+    Ok(true)
+}
+
+fn installed_version(package_dir: &Path) -> Option<String> {
+    let manifest: Value = serde_json::from_slice(&fs::read(package_dir.join("package.json")).ok()?).ok()?;
+    manifest["version"].as_str().map(str::to_string)
+}
+
+fn link_package(target: &Path, link: &Path) -> Result<(), LinkError> {
+    let unchanged = match (fs::canonicalize(link), fs::canonicalize(target)) {
+        (Ok(current), Ok(target)) => current == target,
+        _ => false,
+    };
+    if unchanged && fs::symlink_metadata(link).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+        return Ok(());
+    }
+    // link_dir replaces whatever is already at the destination so the link
+    // always points at the version we just resolved
+    link_dir(target, link)
+}
+
+// Removes packages from a node_modules directory that the layout no longer
+// puts there, such as transitive dependencies left by the hoisted layout.
+// Dot-directories belong to qnpm and other tools and are left alone.
+fn prune_node_modules(node_modules: &Path, keep: &BTreeSet<String>) -> std::io::Result<()> {
+    for name in installed_packages(node_modules)? {
+        if !keep.contains(&name) {
+            remove_package_link(node_modules, &name)?;
+        }
+    }
+    Ok(())
+}
+
+fn installed_packages(node_modules: &Path) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    let entries = match fs::read_dir(node_modules) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        if name.starts_with('@') && entry.file_type()?.is_dir() {
+            for scoped in fs::read_dir(entry.path())? {
+                names.push(format!("{}/{}", name, scoped?.file_name().to_string_lossy()));
+            }
+        } else {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+fn prune_virtual_store(virtual_store: &Path, lockfile: &Lockfile) -> std::io::Result<()> {
+    let expected: BTreeSet<String> = lockfile
+        .packages
+        .values()
+        .map(|package| virtual_store_entry(&package.name, &package.version))
+        .collect();
+    let entries = match fs::read_dir(virtual_store) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !expected.contains(entry.file_name().to_string_lossy().as_ref()) {
+            remove_link(&entry.path())?;
+        }
+    }
+    Ok(())
+}
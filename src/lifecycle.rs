@@ -0,0 +1,234 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use thiserror::Error;
+
+use crate::build_policy::{BuildPolicy, PendingBuilds};
+use crate::layout::ImportedPackage;
+use crate::link::remove_link;
+use crate::lockfile::{package_key, Lockfile};
+use crate::config::Config;
+use crate::run::script_command;
+use crate::store::unshare_files;
+use crate::workspace::find_workspaces;
+
+#[derive(Debug, Error)]
+pub enum LifecycleError {
+    #[error("Failed to start the {event} script of {package}: {source}")]
+    SpawnFailed {
+        package: String,
+        event: String,
+        source: std::io::Error,
+    },
+    #[error("The {event} script of {package} failed ({status}):\n{output}")]
+    ScriptFailed {
+        package: String,
+        event: String,
+        status: String,
+        output: String,
+    },
+    #[error("Failed to copy {package} out of the store before running its install scripts: {source}")]
+    CopyFailed {
+        package: String,
+        source: std::io::Error,
+    },
+    #[error("Failed to record the packages with blocked install scripts: {0}")]
+    PendingBuilds(std::io::Error),
+}
+
+// Scripts npm runs for a dependency once it is in node_modules
+const DEPENDENCY_EVENTS: [&str; 3] = ["preinstall", "install", "postinstall"];
+// And for the project itself after its dependencies are installed
+const PROJECT_EVENTS: [&str; 6] = ["preinstall", "install", "postinstall", "preprepare", "prepare", "postprepare"];
+
+// Runs the install scripts of freshly imported packages the build policy
+// allows, dependencies before their dependents. Output is captured and only
+// shown when a script fails. A package whose script failed is removed again so
+// the next install retries it, which only fails the install when the package
+// isn't optional. Blocked packages are remembered for
+// `qnpm approve-builds`, and ones blocked earlier run once they are allowed.
+pub fn run_dependency_scripts(
+    lockfile: &Lockfile,
+    imported: &[ImportedPackage],
+    current_dir: &Path,
+    config: &Config,
+) -> Result<(), LifecycleError> {
+    let policy = BuildPolicy::load(config, current_dir);
+    let mut pending = PendingBuilds::load(current_dir);
+    let mut by_key: BTreeMap<String, BTreeSet<PathBuf>> = pending
+        .take_decided(lockfile, &policy)
+        .into_iter()
+        .map(|(key, dirs)| (key, dirs.into_iter().map(|dir| current_dir.join(dir)).collect()))
+        .collect();
+    for package in imported {
+        by_key.entry(package.key.clone()).or_default().insert(package.dir.clone());
+    }
+
+    for key in dependency_order(lockfile) {
+        let Some(dirs) = by_key.get(&key) else {
+            continue;
+        };
+        let package = &lockfile.packages[&key];
+        for dir in dirs {
+            let Some(manifest) = read_manifest(dir) else {
+                continue;
+            };
+            let scripts = dependency_scripts(&manifest, dir);
+            if scripts.is_empty() {
+                continue;
+            }
+            if !policy.allows(&package.name) {
+                if !policy.ignores(&package.name) {
+                    pending.add(&key, dir.strip_prefix(current_dir).unwrap_or(dir).to_path_buf());
+                }
+                continue;
+            }
+            // Scripts write into the package, which mustn't reach the store
+            let result = unshare_files(dir)
+                .map_err(|source| LifecycleError::CopyFailed {
+                    package: key.clone(),
+                    source,
+                })
+                .and_then(|()| run_install_scripts(&key, &scripts, &manifest, dir, current_dir, config));
+            if let Err(e) = result {
+                let _ = remove_link(dir);
+                // An optional package failing to build is left out instead
+                if !package.optional {
+                    return Err(e);
+                }
+                println!("Skipping optional dependency {}: {}", key, e);
+            }
+        }
+    }
+
+    pending.save(current_dir).map_err(LifecycleError::PendingBuilds)?;
+    if !pending.is_empty() {
+        let keys: Vec<&str> = pending.keys().map(String::as_str).collect();
+        println!("Install scripts blocked for: {}", keys.join(", "));
+        println!("Run `qnpm approve-builds` to allow them");
+    }
+    Ok(())
+}
+
+fn run_install_scripts(
+    key: &str,
+    scripts: &BTreeMap<&'static str, String>,
+    manifest: &Value,
+    dir: &Path,
+    current_dir: &Path,
+    config: &Config,
+) -> Result<(), LifecycleError> {
+    for event in DEPENDENCY_EVENTS {
+        let Some(script) = scripts.get(event) else {
+            continue;
+        };
+        println!("Running {} script of {}", event, key);
+        let output = script_command(script, event, manifest, dir, current_dir, config)
+            .output()
+            .map_err(|source| LifecycleError::SpawnFailed {
+                package: key.to_string(),
+                event: event.to_string(),
+                source,
+            })?;
+        if !output.status.success() {
+            return Err(LifecycleError::ScriptFailed {
+                package: key.to_string(),
+                event: event.to_string(),
+                status: output.status.to_string(),
+                output: combined_output(&output),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Runs the install scripts of the workspace members and then of the project
+// itself, with the output going straight to the terminal
+pub fn run_project_scripts(current_dir: &Path, config: &Config) -> Result<(), LifecycleError> {
+    // A broken workspace would have failed the install already
+    let members = find_workspaces(current_dir).unwrap_or_default();
+    for dir in members.iter().map(|member| member.dir.as_path()).chain([current_dir]) {
+        run_package_scripts(dir, current_dir, config)?;
+    }
+    Ok(())
+}
+
+fn run_package_scripts(package_dir: &Path, current_dir: &Path, config: &Config) -> Result<(), LifecycleError> {
+    let Some(manifest) = read_manifest(package_dir) else {
+        return Ok(());
+    };
+    let package = manifest["name"].as_str().unwrap_or("the project").to_string();
+    for event in PROJECT_EVENTS {
+        let Some(script) = manifest["scripts"][event].as_str() else {
+            continue;
+        };
+        println!("> {} {}\n> {}", package, event, script);
+        let status = script_command(script, event, &manifest, package_dir, current_dir, config)
+            .status()
+            .map_err(|source| LifecycleError::SpawnFailed {
+                package: package.clone(),
+                event: event.to_string(),
+                source,
+            })?;
+        if !status.success() {
+            return Err(LifecycleError::ScriptFailed {
+                package: package.clone(),
+                event: event.to_string(),
+                status: status.to_string(),
+                output: String::new(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn read_manifest(package_dir: &Path) -> Option<Value> {
+    serde_json::from_slice(&fs::read(package_dir.join("package.json")).ok()?).ok()
+}
+
+// A package with a binding.gyp and no install scripts of its own gets built
+// with node-gyp, like npm does
+fn dependency_scripts(manifest: &Value, package_dir: &Path) -> BTreeMap<&'static str, String> {
+    let mut scripts: BTreeMap<&'static str, String> = DEPENDENCY_EVENTS
+        .iter()
+        .filter_map(|event| Some((*event, manifest["scripts"][*event].as_str()?.to_string())))
+        .collect();
+    if !scripts.contains_key("install")
+        && !scripts.contains_key("preinstall")
+        && package_dir.join("binding.gyp").exists()
+    {
+        scripts.insert("install", "node-gyp rebuild".to_string());
+    }
+    scripts
+}
+
+fn combined_output(output: &Output) -> String {
+    let mut combined = String::from_utf8_lossy(&output.stdout).into_owned();
+    combined.push_str(&String::from_utf8_lossy(&output.stderr));
+    combined.trim_end().to_string()
+}
+
+// Package keys ordered so every package comes after the packages it depends
+// on. Cycles are broken wherever they are entered.
+fn dependency_order(lockfile: &Lockfile) -> Vec<String> {
+    let mut order = Vec::new();
+    let mut visited = HashSet::new();
+    for key in lockfile.packages.keys() {
+        visit(lockfile, key, &mut visited, &mut order);
+    }
+    order
+}
+
+fn visit(lockfile: &Lockfile, key: &str, visited: &mut HashSet<String>, order: &mut Vec<String>) {
+    if !visited.insert(key.to_string()) {
+        return;
+    }
+    if let Some(package) = lockfile.packages.get(key) {
+        for (name, version) in &package.dependencies {
+            visit(lockfile, &package_key(name, version), visited, order);
+        }
+        order.push(key.to_string());
+    }
+}
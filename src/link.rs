@@ -0,0 +1,125 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum LinkError {
+    #[error("Link source does not exist: {0}")]
+    MissingSource(PathBuf),
+    #[error("Failed to create parent directory for {0}: {1}")]
+    ParentCreationFailed(PathBuf, io::Error),
+    #[error("Failed to remove existing entry at {0}: {1}")]
+    RemoveExistingFailed(PathBuf, io::Error),
+    #[error("Failed to link {src} to {dst}: {source}")]
+    LinkFailed {
+        src: PathBuf,
+        dst: PathBuf,
+        source: io::Error,
+    },
+}
+
+// Links the directory `src` to `dst`, replacing whatever is currently at `dst`.
+// Unix uses a relative symlink so the project can be moved, Windows tries a directory symlink, then a junction
+// (which doesn't need developer mode), then falls back to copying the tree.
+pub fn link_dir(src: &Path, dst: &Path) -> Result<(), LinkError> {
+    if !src.exists() {
+        return Err(LinkError::MissingSource(src.to_path_buf()));
+    }
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| LinkError::ParentCreationFailed(dst.to_path_buf(), e))?;
+    }
+    remove_link(dst).map_err(|e| LinkError::RemoveExistingFailed(dst.to_path_buf(), e))?;
+    platform_link_dir(src, dst).map_err(|source| LinkError::LinkFailed {
+        src: src.to_path_buf(),
+        dst: dst.to_path_buf(),
+        source,
+    })
+}
+
+// Removes a link, junction or directory at `path`. Links are removed without
+// touching their target.
+pub fn remove_link(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if metadata.file_type().is_symlink() {
+        // Directory symlinks on Windows have to be removed as directories
+        fs::remove_file(path).or_else(|_| fs::remove_dir(path))
+    } else if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+#[cfg(unix)]
+fn platform_link_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    let target = match dst.parent() {
+        Some(parent) => relative_path(src, parent),
+        None => src.to_path_buf(),
+    };
+    std::os::unix::fs::symlink(target, dst)
+}
+
+// The path leading from the directory `base` to `target`, both absolute
+pub fn relative_path(target: &Path, base: &Path) -> PathBuf {
+    let target_components: Vec<_> = target.components().collect();
+    let base_components: Vec<_> = base.components().collect();
+    let common = target_components
+        .iter()
+        .zip(&base_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut path = PathBuf::new();
+    for _ in common..base_components.len() {
+        path.push("..");
+    }
+    for component in &target_components[common..] {
+        path.push(component);
+    }
+    path
+}
+
+#[cfg(windows)]
+fn platform_link_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    if std::os::windows::fs::symlink_dir(src, dst).is_ok() {
+        return Ok(());
+    }
+    let junction = std::process::Command::new("cmd")
+        .arg("/C")
+        .arg("mklink")
+        .arg("/J")
+        .arg(dst)
+        .arg(src)
+        .output();
+    if let Ok(output) = junction {
+        if output.status.success() {
+            return Ok(());
+        }
+    }
+    copy_dir_all(src, dst)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn platform_link_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    copy_dir_all(src, dst)
+}
+
+#[cfg(not(unix))]
+fn copy_dir_all(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_all(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
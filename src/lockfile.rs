@@ -0,0 +1,373 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::semver::{Range, Version};
+
+pub const LOCKFILE_NAME: &str = "qnpm-lock.json";
+const LOCKFILE_VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum LockfileError {
+    #[error("Failed to read lockfile {0}: {1}")]
+    ReadFailed(PathBuf, std::io::Error),
+    #[error("Failed to parse lockfile {0}: {1}")]
+    ParseFailed(PathBuf, serde_json::Error),
+    #[error("Unsupported lockfile version {0}, expected {1}")]
+    UnsupportedVersion(u32, u32),
+    #[error("Failed to serialize lockfile: {0}")]
+    SerializeFailed(serde_json::Error),
+    #[error("Failed to write lockfile {0}: {1}")]
+    WriteFailed(PathBuf, std::io::Error),
+    #[error("No {} found, run `qnpm install` to create one", LOCKFILE_NAME)]
+    Missing,
+    #[error("{} is out of date with package.json:\n  {}", LOCKFILE_NAME, .0.join("\n  "))]
+    OutOfDate(Vec<String>),
+}
+
+// The root project's path among the importers
+pub const ROOT_IMPORTER: &str = ".";
+
+// Dependency specs from package.json of every importer, the root project and
+// each workspace member, by their path relative to the root
+pub type ImporterSpecifiers = BTreeMap<String, DependencySpecs>;
+
+// What the package.json of an importer asks for
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DependencySpecs {
+    // Every dependency spec, name to spec
+    pub specifiers: BTreeMap<String, String>,
+    // Names among the specifiers from devDependencies
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub dev: BTreeSet<String>,
+    // And from optionalDependencies
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub optional: BTreeSet<String>,
+}
+
+impl DependencySpecs {
+    // The package.json field a dependency is listed in
+    pub fn field(&self, name: &str) -> &'static str {
+        if self.optional.contains(name) {
+            "optionalDependencies"
+        } else if self.dev.contains(name) {
+            "devDependencies"
+        } else {
+            "dependencies"
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lockfile {
+    pub lockfile_version: u32,
+    #[serde(flatten)]
+    pub root: Importer,
+    // Workspace members by their path relative to the root
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub workspaces: BTreeMap<String, Importer>,
+    // Every resolved package keyed by `name@version`
+    pub packages: BTreeMap<String, LockedPackage>,
+}
+
+// A package.json dependencies are installed for
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Importer {
+    // Package name of a workspace member, the root has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // Dependency specs from package.json the tree was resolved from
+    #[serde(flatten)]
+    pub specs: DependencySpecs,
+    // Direct dependencies from the registry, name to resolved version
+    pub dependencies: BTreeMap<String, String>,
+    // Direct dependencies on workspace members, name to the member's path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub links: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    pub resolved: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<String>,
+    // Dependency name to the resolved version of that dependency
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<String, String>,
+    // Names among the dependencies the package lists as optional
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub optional_dependencies: BTreeSet<String>,
+    // Only needed for the devDependencies of the project or its members
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dev: bool,
+    // Only reachable through optional dependencies, so failing to install it
+    // doesn't fail the install
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+}
+
+pub fn package_key(name: &str, version: &str) -> String {
+    format!("{}@{}", name, version)
+}
+
+impl Lockfile {
+    pub fn new(specs: DependencySpecs) -> Self {
+        Self {
+            lockfile_version: LOCKFILE_VERSION,
+            root: Importer {
+                specs,
+                ..Importer::default()
+            },
+            workspaces: BTreeMap::new(),
+            packages: BTreeMap::new(),
+        }
+    }
+
+    // The root project first, then the workspace members
+    pub fn importers(&self) -> impl Iterator<Item = (&str, &Importer)> {
+        std::iter::once((ROOT_IMPORTER, &self.root))
+            .chain(self.workspaces.iter().map(|(path, importer)| (path.as_str(), importer)))
+    }
+
+    pub fn importer(&self, path: &str) -> Option<&Importer> {
+        if path == ROOT_IMPORTER {
+            Some(&self.root)
+        } else {
+            self.workspaces.get(path)
+        }
+    }
+
+    pub fn importer_mut(&mut self, path: &str) -> Option<&mut Importer> {
+        if path == ROOT_IMPORTER {
+            Some(&mut self.root)
+        } else {
+            self.workspaces.get_mut(path)
+        }
+    }
+
+    pub fn path(project_dir: &Path) -> PathBuf {
+        project_dir.join(LOCKFILE_NAME)
+    }
+
+    // Returns `None` when the project has no lockfile yet
+    pub fn load(project_dir: &Path) -> Result<Option<Self>, LockfileError> {
+        let path = Self::path(project_dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| LockfileError::ReadFailed(path.clone(), e))?;
+        let lockfile: Self = serde_json::from_str(&contents)
+            .map_err(|e| LockfileError::ParseFailed(path.clone(), e))?;
+        if lockfile.lockfile_version != LOCKFILE_VERSION {
+            return Err(LockfileError::UnsupportedVersion(lockfile.lockfile_version, LOCKFILE_VERSION));
+        }
+        Ok(Some(lockfile))
+    }
+
+    pub fn save(&self, project_dir: &Path) -> Result<(), LockfileError> {
+        let path = Self::path(project_dir);
+        // BTreeMaps keep the output sorted, so the same tree always serializes
+        // to the same bytes
+        let mut contents = serde_json::to_string_pretty(self).map_err(LockfileError::SerializeFailed)?;
+        contents.push('\n');
+        std::fs::write(&path, contents).map_err(|e| LockfileError::WriteFailed(path, e))
+    }
+
+    // The lock can be installed as-is when it was resolved from exactly the
+    // specs currently in the package.json files and every package it
+    // references is present
+    pub fn is_up_to_date(&self, specifiers: &ImporterSpecifiers) -> bool {
+        let same_importers = self.importers().map(|(path, _)| path).eq(specifiers.keys().map(String::as_str));
+        if !same_importers {
+            return false;
+        }
+        let importers_complete = self.importers().all(|(path, importer)| {
+            let roots_present = importer
+                .dependencies
+                .iter()
+                .all(|(name, version)| self.packages.contains_key(&package_key(name, version)));
+            // Optional dependencies that failed to resolve are missing for good
+            let required_present = importer
+                .specs
+                .specifiers
+                .keys()
+                .filter(|name| !importer.specs.optional.contains(*name))
+                .all(|name| importer.dependencies.contains_key(name) || importer.links.contains_key(name));
+            let all_specified = importer
+                .dependencies
+                .keys()
+                .chain(importer.links.keys())
+                .all(|name| importer.specs.specifiers.contains_key(name));
+            Some(&importer.specs) == specifiers.get(path) && roots_present && required_present && all_specified
+        });
+        let edges_present = self.packages.values().all(|package| {
+            package
+                .dependencies
+                .iter()
+                .all(|(name, version)| self.packages.contains_key(&package_key(name, version)))
+        });
+        importers_complete && edges_present
+    }
+
+    // Describes every way the package.json files disagree with the lock,
+    // empty when the lock is up to date
+    pub fn differences(&self, specifiers: &ImporterSpecifiers) -> Vec<String> {
+        let mut differences = Vec::new();
+        for (path, wanted) in specifiers {
+            // Workspace members' differences say which member they're about
+            let prefix = if path == ROOT_IMPORTER { String::new() } else { format!("{}: ", path) };
+            let Some(importer) = self.importer(path) else {
+                differences.push(format!("{}workspace member missing from the lockfile", prefix));
+                continue;
+            };
+            for (name, spec) in &wanted.specifiers {
+                match importer.specs.specifiers.get(name) {
+                    Some(locked) if locked != spec => differences.push(format!(
+                        "{}{}: package.json wants {} but the lockfile was resolved from {}",
+                        prefix, name, spec, locked
+                    )),
+                    Some(_) if importer.specs.field(name) != wanted.field(name) => differences.push(format!(
+                        "{}{}: package.json lists it in {} but the lockfile in {}",
+                        prefix,
+                        name,
+                        wanted.field(name),
+                        importer.specs.field(name)
+                    )),
+                    Some(_) => {}
+                    None => differences.push(format!("{}{}: missing from the lockfile", prefix, name)),
+                }
+            }
+            for name in importer.specs.specifiers.keys() {
+                if !wanted.specifiers.contains_key(name) {
+                    differences.push(format!("{}{}: locked but no longer in package.json", prefix, name));
+                }
+            }
+        }
+        for path in self.workspaces.keys() {
+            if !specifiers.contains_key(path) {
+                differences.push(format!("{}: locked but no longer a workspace member", path));
+            }
+        }
+        if differences.is_empty() && !self.is_up_to_date(specifiers) {
+            differences.push("lockfile references packages it doesn't contain".to_string());
+        }
+        differences
+    }
+
+    // Highest locked version of `name` that satisfies `spec`, so re-resolving
+    // after package.json changed keeps the versions teammates already have
+    pub fn find_satisfying(&self, name: &str, spec: &str) -> Option<&LockedPackage> {
+        let range = Range::parse(spec).ok()?;
+        self.packages
+            .values()
+            .filter(|package| package.name == name)
+            .filter_map(|package| Version::parse(&package.version).ok().map(|version| (version, package)))
+            .filter(|(version, _)| range.satisfies(version))
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, package)| package)
+    }
+
+    // Flags every package only the devDependencies of the importers need as
+    // `dev`, and every package only reachable through optional dependencies
+    // as `optional`, like npm's lockfile does
+    pub fn mark_dev_and_optional(&mut self) {
+        let mut production_roots = Vec::new();
+        let mut required_roots = Vec::new();
+        for (_, importer) in self.importers() {
+            for (name, version) in &importer.dependencies {
+                let key = package_key(name, version);
+                if !importer.specs.dev.contains(name) {
+                    production_roots.push(key.clone());
+                }
+                if !importer.specs.optional.contains(name) {
+                    required_roots.push(key);
+                }
+            }
+        }
+        let production = self.reachable(production_roots, false);
+        let required = self.reachable(required_roots, true);
+        for (key, package) in self.packages.iter_mut() {
+            package.dev = !production.contains(key);
+            package.optional = !required.contains(key);
+        }
+    }
+
+    // Keys of the packages reachable from `roots`, through the required
+    // dependencies only with `skip_optional`
+    fn reachable(&self, roots: Vec<String>, skip_optional: bool) -> BTreeSet<String> {
+        let mut reached = BTreeSet::new();
+        let mut stack = roots;
+        while let Some(key) = stack.pop() {
+            let Some(package) = self.packages.get(&key) else {
+                continue;
+            };
+            if !reached.insert(key) {
+                continue;
+            }
+            stack.extend(
+                package
+                    .dependencies
+                    .iter()
+                    .filter(|(name, _)| !skip_optional || !package.optional_dependencies.contains(*name))
+                    .map(|(name, version)| package_key(name, version)),
+            );
+        }
+        reached
+    }
+
+    // The part of the lock that gets installed: without the devDependencies
+    // of the importers with `omit_dev`, and without the `removed` packages,
+    // optional ones that failed to install, and whatever only they needed
+    pub fn installable(&self, omit_dev: bool, removed: &BTreeSet<String>) -> Lockfile {
+        let mut lockfile = self.clone();
+        for (_, importer) in lockfile.importers_mut() {
+            let omitted = |name: &String| omit_dev && importer.specs.dev.contains(name);
+            importer
+                .dependencies
+                .retain(|name, version| !omitted(name) && !removed.contains(&package_key(name, version)));
+            importer.links.retain(|name, _| !omitted(name));
+        }
+        for package in lockfile.packages.values_mut() {
+            package
+                .dependencies
+                .retain(|name, version| !removed.contains(&package_key(name, version)));
+        }
+        let roots = lockfile
+            .importers()
+            .flat_map(|(_, importer)| importer.dependencies.iter().map(|(name, version)| package_key(name, version)))
+            .collect();
+        let reached = lockfile.reachable(roots, false);
+        lockfile.packages.retain(|key, _| reached.contains(key));
+        lockfile
+    }
+
+    fn importers_mut(&mut self) -> impl Iterator<Item = (&str, &mut Importer)> {
+        std::iter::once((ROOT_IMPORTER, &mut self.root))
+            .chain(self.workspaces.iter_mut().map(|(path, importer)| (path.as_str(), importer)))
+    }
+
+    // Copies a locked package and everything it depends on from another lock
+    pub fn copy_subtree(&mut self, from: &Lockfile, key: &str) {
+        let mut stack = vec![key.to_string()];
+        while let Some(key) = stack.pop() {
+            if self.packages.contains_key(&key) {
+                continue;
+            }
+            if let Some(package) = from.packages.get(&key) {
+                stack.extend(
+                    package
+                        .dependencies
+                        .iter()
+                        .map(|(name, version)| package_key(name, version)),
+                );
+                self.packages.insert(key, package.clone());
+            }
+        }
+    }
+}
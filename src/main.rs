@@ -1,7 +1,6 @@
 use std::error::Error;
 use std::env;
 use std::path::PathBuf;
-use std::collections::HashMap;
 mod config;
 use config::Config;
 use std::time::Instant;
@@ -10,10 +9,32 @@ mod init;
 use std::path::Path;
 use std::sync::Arc;
 mod run;
-use run::run_script;
 mod remove;
 mod uninstall;
-use crate::add::PackageRaw;
+mod link;
+mod semver;
+mod lockfile;
+mod integrity;
+mod registry;
+mod memo;
+mod metadata_cache;
+mod store;
+mod layout;
+mod hoist;
+mod bin_links;
+mod lifecycle;
+mod build_policy;
+mod approve_builds;
+mod exec;
+mod workspace;
+mod workspace_run;
+use workspace::Project;
+use add::SaveType;
+use registry::Registry;
+use store::Store;
+mod npmrc;
+use npmrc::Npmrc;
+use config::{NetworkMode, DEFAULT_REGISTRY};
 
 
 #[tokio::main(flavor = "current_thread")]
@@ -30,112 +51,247 @@ async fn main() -> Result<(), Box<dyn Error>> {
     };
 
     // Directly jump to match if command is neither 'config' nor 'add'
-    if command != "config" && command != "add" && command != "uninstall" && command != "install" {
+    if command != "config" && command != "add" && command != "uninstall" && command != "install" && command != "ci" && command != "approve-builds" && command != "run" && command != "exec" && command != "dlx" {
         let elapsed = start.elapsed().as_secs_f64();
         println!("Elapsed: {:.8?}", elapsed);
-        goto_match(&command, args_iter.collect(), start, PathBuf::from("node_modules")).await;
+        let config = Config {
+            cache_dir: PathBuf::from("node_modules"),
+            ..Config::new()
+        };
+        goto_match(&command, args_iter.collect(), start, config).await;
         return Ok(());
     }
 
     // Only perform the necessary operations for 'config' and 'add' commands
     let current_dir = env::current_dir()?;
-    let config_path = current_dir.join("package_manager_config.json");
+    // Workspace members share the config of the workspace root
+    let config_dir = workspace::find_project(&current_dir, None)
+        .map(|project| project.root)
+        .unwrap_or(current_dir);
+    let config_path = config_dir.join("package_manager_config.json");
     let mut config = Config::load(&config_path)?;
 
+    let mut args: Vec<String> = args_iter.collect();
     if command == "config" {
-        if let Some(new_cache_dir) = parse_config_args(args_iter) {
+        let mut changed = false;
+        if let Some(new_cache_dir) = take_flag_value(&mut args, "--cachedir") {
             config.cache_dir = PathBuf::from(new_cache_dir);
-            config.save(&config_path)?;
             println!("Cache directory updated to: {}", config.cache_dir.display());
+            changed = true;
+        }
+        if let Some(registry) = take_flag_value(&mut args, "--registry") {
+            config.registry = registry;
+            println!("Registry updated to: {}", config.registry);
+            changed = true;
+        }
+        if let Some(network_concurrency) = take_flag_value(&mut args, "--network-concurrency") {
+            config.network_concurrency = network_concurrency.parse()?;
+            println!("Network concurrency updated to: {}", config.network_concurrency);
+            changed = true;
+        }
+        if let Some(fetch_timeout) = take_flag_value(&mut args, "--fetch-timeout") {
+            config.fetch_timeout = fetch_timeout.parse()?;
+            println!("Fetch timeout updated to: {}s", config.fetch_timeout);
+            changed = true;
+        }
+        if let Some(metadata_ttl) = take_flag_value(&mut args, "--metadata-ttl") {
+            config.metadata_ttl = metadata_ttl.parse()?;
+            println!("Metadata cache TTL updated to: {}s", config.metadata_ttl);
+            changed = true;
+        }
+        if let Some(import_method) = take_flag_value(&mut args, "--package-import-method") {
+            config.package_import_method = import_method.parse()?;
+            println!("Package import method updated to: {}", import_method);
+            changed = true;
+        }
+        if let Some(node_linker) = take_flag_value(&mut args, "--node-linker") {
+            config.node_linker = node_linker.parse()?;
+            println!("Node linker updated to: {}", node_linker);
+            changed = true;
+        }
+        if let Some(script_shell) = take_flag_value(&mut args, "--script-shell") {
+            println!("Script shell updated to: {}", script_shell);
+            config.script_shell = Some(script_shell);
+            changed = true;
+        }
+        if let Some(workspace_concurrency) = take_flag_value(&mut args, "--workspace-concurrency") {
+            config.workspace_concurrency = workspace_concurrency.parse()?;
+            println!("Workspace concurrency updated to: {}", config.workspace_concurrency);
+            changed = true;
+        }
+        if changed {
+            config.save(&config_path)?;
         }
     } else {
+        // Everything after `--` belongs to the script being run, not to qnpm
+        let passthrough = match args.iter().position(|arg| arg == "--") {
+            Some(position) => args.split_off(position),
+            None => Vec::new(),
+        };
+        // Command line flags override the environment, which overrides the config file
+        config.apply_env();
+        if let Some(registry) = take_flag_value(&mut args, "--registry") {
+            config.registry = registry;
+        }
+        if let Some(network_concurrency) = take_flag_value(&mut args, "--network-concurrency") {
+            config.network_concurrency = network_concurrency.parse()?;
+        }
+        if let Some(workspace_concurrency) = take_flag_value(&mut args, "--workspace-concurrency") {
+            config.workspace_concurrency = workspace_concurrency.parse()?;
+        }
+        if take_flag(&mut args, "--prefer-offline") {
+            config.network_mode = NetworkMode::PreferOffline;
+        }
+        if take_flag(&mut args, "--offline") {
+            config.network_mode = NetworkMode::Offline;
+        }
+        if take_flag(&mut args, "--ignore-scripts") {
+            config.ignore_scripts = true;
+        }
+        if take_flag(&mut args, "--production") {
+            config.production = true;
+        }
+        while let Some(omit) = take_flag_value(&mut args, "--omit") {
+            match omit.as_str() {
+                "dev" => config.production = true,
+                _ => eprintln!("Ignoring --omit={}, only dev dependencies can be omitted", omit),
+            }
+        }
+        args.extend(passthrough);
         let elapsed = start.elapsed().as_secs_f64();
         println!("Elapsed: {:.8?}", elapsed);
-        goto_match(&command, args_iter.collect(), start, config.cache_dir).await;
+        goto_match(&command, args, start, config).await;
     }
 
     Ok(())
 }
 
-fn parse_config_args(mut args_iter: impl Iterator<Item = String>) -> Option<String> {
-    let mut cache_dir = None;
-    while let Some(arg) = args_iter.next() {
-        if arg == "--cachedir" {
-            cache_dir = args_iter.next();
-            break;
-        }
+// Removes `--flag value` or `--flag=value` from the arguments and returns the value
+fn take_flag_value(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let prefix = format!("{}=", flag);
+    let position = args.iter().position(|arg| arg == flag || arg.starts_with(&prefix))?;
+    let arg = args.remove(position);
+    match arg.strip_prefix(&prefix) {
+        Some(value) => Some(value.to_string()),
+        None if position < args.len() => Some(args.remove(position)),
+        None => None,
     }
-    cache_dir
+}
+
+// Removes a boolean `--flag` from the arguments, returning whether it was given
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != before
 }
 
 
-async fn goto_match(command: &str, args: Vec<String>, start: Instant, cache_dir: PathBuf) {
+async fn goto_match(command: &str, mut args: Vec<String>, start: Instant, config: Config) {
+    let store = Arc::new(Store::new(&config));
     match command {
         "add" => {
             let current_dir: PathBuf = env::current_dir().unwrap();
-            // Extract package names from args if command is 'add'
-            let package_names: Vec<String> = args.iter().cloned().collect();
 
             // if package.json doesn't exist, create it
             if !Path::new("package.json").exists() {
-                init::create_bare_package_json(&current_dir);
+                if let Err(e) = init::create_bare_package_json(&current_dir) {
+                    eprintln!("Error creating package.json: {}", e);
+                    return;
+                }
             }
 
-            if !Path::new("node_modules").exists() {
-                std::fs::create_dir_all("node_modules").unwrap();
+            let Some(project) = open_project(&mut args) else {
+                return;
+            };
+            // Every save flag is taken out of the args before deciding, so none
+            // of them is mistaken for a package name
+            let save_flags = [
+                (take_flag(&mut args, "--save-prod") | take_flag(&mut args, "-P"), SaveType::Prod),
+                (take_flag(&mut args, "--save-dev") | take_flag(&mut args, "-D"), SaveType::Dev),
+                (take_flag(&mut args, "--save-optional") | take_flag(&mut args, "-O"), SaveType::Optional),
+                (take_flag(&mut args, "--save-peer"), SaveType::Peer),
+            ];
+            let mut save_types = save_flags.into_iter().filter(|(given, _)| *given).map(|(_, save_type)| save_type);
+            // Saving to dependencies is the default
+            let save_type = save_types.next().unwrap_or(SaveType::Prod);
+            if save_types.next().is_some() {
+                eprintln!("Only one of --save-prod, --save-dev, --save-optional and --save-peer can be given");
+                std::process::exit(1);
             }
+            // Extract package names from args if command is 'add'
+            let package_names: Vec<String> = args.to_vec();
 
-            // Make sure cache_dir also has node_modules
-            if !Path::new(&cache_dir.join("node_modules")).exists() {
-                std::fs::create_dir_all(&cache_dir.join("node_modules")).unwrap();
+            if !project.root.join("node_modules").exists() {
+                std::fs::create_dir_all(project.root.join("node_modules")).unwrap();
             }
 
-            // Wrap params in Arc and call the new function
+            let Some(registry) = open_registry(&project.root, &config) else {
+                return;
+            };
+
             if let Err(e) = add::add_packages_with_dependencies_from_names(
                 &package_names,
-                Arc::new(current_dir),
-                Arc::new(cache_dir),
+                save_type,
+                &project,
+                store,
+                registry,
+                &config,
             )
             .await
             {
                 eprintln!("Error adding packages: {}", e);
+                std::process::exit(1);
             }
         },
         "install" => 
         {
-            // Get current directory and package.json
-            let current_dir: PathBuf = env::current_dir().unwrap();
-            let package_json_path: PathBuf = current_dir.join("package.json");
-            let package_json_str = std::fs::read_to_string(&package_json_path).unwrap();
-            let package_json_value: serde_json::Value = serde_json::from_str(&package_json_str).unwrap();
-
-            let dependencies = package_json_value["dependencies"].as_object().unwrap();
-            let mut package_raws: Vec<PackageRaw> = Vec::new();
-
-            for (package_name, package_version) in dependencies {
-                let package_raw = PackageRaw {
-                    name: package_name.to_string(),
-                    version: package_version.as_str().unwrap().to_string(),
-                };
-                package_raws.push(package_raw);
-            }
-
-            if let Err(e) = add::add_packages_with_dependencies_from_names_with_version(
-                package_raws.as_slice(),
-                Arc::new(current_dir),
-                Arc::new(cache_dir),
-            ).await {
-                eprintln!("Error installing packages: {}", e);
+            // Installs cover the whole workspace, wherever in it they're run
+            let Some(project) = open_project(&mut args) else {
+                std::process::exit(1);
+            };
+            let current_dir = project.root;
+            let Some(registry) = open_registry(&current_dir, &config) else {
+                std::process::exit(1);
+            };
+            if take_flag(&mut args, "--frozen-lockfile") {
+                install_frozen(current_dir, store, registry, &config).await;
+            } else {
+                if let Err(e) = add::install_project(
+                    Arc::new(current_dir.clone()),
+                    store,
+                    registry,
+                    &config,
+                ).await {
+                    eprintln!("Error installing packages: {}", e);
+                    std::process::exit(1);
+                }
+                if !config.ignore_scripts {
+                    if let Err(e) = lifecycle::run_project_scripts(&current_dir, &config) {
+                        eprintln!("Error running install scripts: {}", e);
+                        std::process::exit(1);
+                    }
+                }
             }
             },
+        "ci" => {
+            let Some(project) = open_project(&mut args) else {
+                std::process::exit(1);
+            };
+            let current_dir = project.root;
+            let Some(registry) = open_registry(&current_dir, &config) else {
+                std::process::exit(1);
+            };
+            install_frozen(current_dir, store, registry, &config).await;
+        },
         "remove" => 
         {
-            let current_dir: PathBuf = env::current_dir().unwrap();
-
             if !Path::new("package.json").exists() {
                 println!("package.json not found");
                 return;
             }
+            let Some(project) = open_project(&mut args) else {
+                return;
+            };
 
             if args.is_empty() {
                 println!("Usage: qnpm remove <package_name>");
@@ -144,19 +300,21 @@ async fn goto_match(command: &str, args: Vec<String>, start: Instant, cache_dir:
 
             //for loop package names and remove them
             for package_name in args {
-                if let Err(e) = remove::remove(&package_name, &current_dir) {
+                if let Err(e) = remove::remove(&package_name, &project.dir) {
                     eprintln!("Error removing package: {}", e);
                 }
             }
+            install_after_edit(project.root, store, &config).await;
         },
         "uninstall" => 
         {
-            let current_dir: PathBuf = env::current_dir().unwrap();
-    
             if !Path::new("package.json").exists() {
                 println!("package.json not found");
                 return;
             }
+            let Some(project) = open_project(&mut args) else {
+                return;
+            };
 
             if args.is_empty() {
                 println!("Usage: qnpm uninstall <package_name>");
@@ -165,30 +323,178 @@ async fn goto_match(command: &str, args: Vec<String>, start: Instant, cache_dir:
             println!("Uninstalling packages");
             //for loop package names and uninstall them
             for package_name in args {
-                if let Err(e) = uninstall::uninstall(&package_name, &current_dir, &cache_dir) {
+                if let Err(e) = uninstall::uninstall(&package_name, &project, &store) {
                     eprintln!("Error uninstalling package: {}", e);
                 }
             }
+            install_after_edit(project.root, store, &config).await;
         },
         "run" => {
             let current_dir: PathBuf = env::current_dir().unwrap();
+            // Flags after `--` belong to the script
+            let passthrough = match args.iter().position(|arg| arg == "--") {
+                Some(position) => args.split_off(position),
+                None => Vec::new(),
+            };
+            let recursive = take_flag(&mut args, "--recursive") | take_flag(&mut args, "-r");
+            let mut filters = Vec::new();
+            while let Some(filter) = take_flag_value(&mut args, "--filter") {
+                filters.push(filter);
+            }
+            if args.is_empty() {
+                println!("Usage: qnpm run <script_name> [--recursive] [--filter <pattern>]");
+                return;
+            }
+            let script_name = args.remove(0);
+            let script_args: Vec<String> = args.into_iter().chain(passed_args(&passthrough).to_vec()).collect();
+
+            // The script's exit code becomes qnpm's
+            let result = if recursive || !filters.is_empty() {
+                let Some(project) = open_project(&mut Vec::new()) else {
+                    return;
+                };
+                workspace_run::run_workspaces(&project.root, &current_dir, &script_name, &script_args, &filters, &config)
+            } else {
+                if !current_dir.join("package.json").exists() {
+                    println!("package.json not found");
+                    return;
+                }
+                run::run_script(&current_dir, &script_name, &script_args, &config)
+            };
+            match result {
+                Ok(0) => {}
+                Ok(code) => std::process::exit(code),
+                Err(e) => {
+                    eprintln!("Error running script: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        "exec" => {
+            let current_dir: PathBuf = env::current_dir().unwrap();
+            let args = passed_args(&args);
             if args.is_empty() {
-                println!("Usage: qnpm run <script_name>");
+                println!("Usage: qnpm exec <command> [args]");
                 return;
             }
-            if !current_dir.join("package.json").exists() {
-                println!("package.json not found");
+            match exec::exec(&current_dir, &args[0], &args[1..], &config) {
+                Ok(0) => {}
+                Ok(code) => std::process::exit(code),
+                Err(e) => {
+                    eprintln!("Error running command: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        "dlx" => {
+            let current_dir: PathBuf = env::current_dir().unwrap();
+            if args.is_empty() {
+                println!("Usage: qnpm dlx <package>[@range] [args]");
                 return;
             }
-            let package_json_path: PathBuf = current_dir.join("package.json");
-            run_script(&package_json_path, &args[0]).unwrap();
+            let root = workspace::find_project(&current_dir, None)
+                .map(|project| project.root)
+                .unwrap_or_else(|_| current_dir.clone());
+            let Some(registry) = open_registry(&root, &config) else {
+                std::process::exit(1);
+            };
+            match exec::dlx(&current_dir, &args[0], passed_args(&args[1..]), store, registry, &config).await {
+                Ok(0) => {}
+                Ok(code) => std::process::exit(code),
+                Err(e) => {
+                    eprintln!("Error running package: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        "approve-builds" => {
+            let Some(project) = open_project(&mut args) else {
+                return;
+            };
+            if let Err(e) = approve_builds::approve_builds(&project.root, &config, &args) {
+                eprintln!("Error approving builds: {}", e);
+            }
         },
         "init" => {
             let current_dir: PathBuf = env::current_dir().unwrap();
-            init::initialize_node(&current_dir);
+            if let Err(e) = init::initialize_node(&current_dir) {
+                eprintln!("Error initializing package.json: {}", e);
+            }
         },
         _ => println!("Command not found")
     }
     let elapsed = start.elapsed().as_secs_f64();
     println!("Elapsed: {:.8?}", elapsed);
 }
+
+// The project a command works on, a workspace member when run from inside one
+// or given `--workspace <name or path>`
+fn open_project(args: &mut Vec<String>) -> Option<Project> {
+    let workspace = take_flag_value(args, "--workspace").or_else(|| take_flag_value(args, "-w"));
+    let current_dir = env::current_dir().ok()?;
+    match workspace::find_project(&current_dir, workspace.as_deref()) {
+        Ok(project) => Some(project),
+        Err(e) => {
+            eprintln!("Error finding the project: {}", e);
+            None
+        }
+    }
+}
+
+// npm takes `--` before the arguments meant for the command being run
+fn passed_args(args: &[String]) -> &[String] {
+    match args.first() {
+        Some(first) if first == "--" => &args[1..],
+        _ => args,
+    }
+}
+
+// Sets up the registry client from qnpm's config and the .npmrc files, which
+// only pick the registry when qnpm's own config doesn't. Like the config, the
+// project's .npmrc is the one at the workspace root.
+fn open_registry(root: &Path, config: &Config) -> Option<Arc<Registry>> {
+    let npmrc = match Npmrc::load(root) {
+        Ok(npmrc) => npmrc,
+        Err(e) => {
+            eprintln!("Error reading .npmrc: {}", e);
+            return None;
+        }
+    };
+    let registry_url = match &npmrc.registry {
+        Some(registry) if config.registry == DEFAULT_REGISTRY => registry.clone(),
+        _ => config.registry.clone(),
+    };
+    match Registry::new(&registry_url, npmrc, config) {
+        Ok(registry) => Some(Arc::new(registry)),
+        Err(e) => {
+            eprintln!("Error setting up the registry client: {}", e);
+            None
+        }
+    }
+}
+
+// Re-resolves the whole workspace after package.json files were edited, so
+// the root lockfile and node_modules drop what they no longer list
+async fn install_after_edit(root: PathBuf, store: Arc<Store>, config: &Config) {
+    let Some(registry) = open_registry(&root, config) else {
+        std::process::exit(1);
+    };
+    if let Err(e) = add::install_project(Arc::new(root), store, registry, config).await {
+        eprintln!("Error installing packages: {}", e);
+        std::process::exit(1);
+    }
+}
+
+// CI installs must fail the build, so errors exit with a non-zero status
+async fn install_frozen(current_dir: PathBuf, store: Arc<Store>, registry: Arc<Registry>, config: &Config) {
+    if let Err(e) = add::install_frozen_lockfile(Arc::new(current_dir.clone()), store, registry, config).await {
+        eprintln!("Error installing from lockfile: {}", e);
+        std::process::exit(1);
+    }
+    if !config.ignore_scripts {
+        if let Err(e) = lifecycle::run_project_scripts(&current_dir, config) {
+            eprintln!("Error running install scripts: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+// Remembers the result of an async operation per key for the rest of the run.
+// Callers asking for a key that is still being computed wait for that
+// computation instead of starting their own. Failures aren't remembered, the
+// next caller tries again.
+pub struct Memo<T> {
+    cells: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+}
+
+impl<T: Clone> Memo<T> {
+    pub fn new() -> Self {
+        Self {
+            cells: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn get_or_try_init<E, F, Fut>(&self, key: &str, init: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let cell = {
+            let mut cells = self.cells.lock().expect("memo lock poisoned");
+            Arc::clone(cells.entry(key.to_string()).or_default())
+        };
+        cell.get_or_try_init(init).await.cloned()
+    }
+}
@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Packuments kept on disk between runs, together with the validators the
+// registry sent so a stale copy can be revalidated instead of downloaded again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedPackument {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    // Seconds since the epoch the registry last confirmed this copy
+    pub fetched_at: u64,
+    pub packument: Value,
+}
+
+pub struct MetadataCache {
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl CachedPackument {
+    pub fn new(packument: Value, etag: Option<String>, last_modified: Option<String>) -> Self {
+        Self {
+            etag,
+            last_modified,
+            fetched_at: now(),
+            packument,
+        }
+    }
+}
+
+impl MetadataCache {
+    pub fn new(cache_dir: &Path, ttl: Duration) -> Self {
+        Self {
+            dir: cache_dir.join("metadata"),
+            ttl,
+        }
+    }
+
+    // One file per packument URL, grouped by registry host:
+    // `metadata/registry.npmjs.org/@scope%2fname.json`
+    fn path(&self, url: &str) -> Option<PathBuf> {
+        let url = url::Url::parse(url).ok()?;
+        let host = match url.port() {
+            Some(port) => format!("{}_{}", url.host_str()?, port),
+            None => url.host_str()?.to_string(),
+        };
+        let file_name = url.path().trim_start_matches('/').replace('/', "%2f");
+        Some(self.dir.join(host).join(format!("{}.json", file_name)))
+    }
+
+    pub fn load(&self, url: &str) -> Option<CachedPackument> {
+        let contents = std::fs::read(self.path(url)?).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    // Failing to write the cache only costs a download next time, so errors
+    // are ignored
+    pub fn store(&self, url: &str, cached: &CachedPackument) {
+        let Some(path) = self.path(url) else {
+            return;
+        };
+        let Ok(contents) = serde_json::to_vec(cached) else {
+            return;
+        };
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        // Write then rename so parallel qnpm processes never read half a file
+        let temp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+        if std::fs::write(&temp_path, contents).is_ok() && std::fs::rename(&temp_path, &path).is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+    }
+
+    pub fn is_fresh(&self, cached: &CachedPackument) -> bool {
+        now().saturating_sub(cached.fetched_at) < self.ttl.as_secs()
+    }
+}
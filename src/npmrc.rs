@@ -0,0 +1,172 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum NpmrcError {
+    #[error("Failed to read {0}: {1}")]
+    ReadFailed(PathBuf, std::io::Error),
+    #[error("Failed to replace env in {0}: ${{{1}}} is not set")]
+    MissingEnv(PathBuf, String),
+}
+
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    // `_authToken`, sent as a bearer token
+    Token(String),
+    // `_auth` or `username` + `_password`, sent as basic auth
+    Basic(String),
+}
+
+impl Credentials {
+    pub fn header_value(&self) -> String {
+        match self {
+            Credentials::Token(token) => format!("Bearer {}", token),
+            Credentials::Basic(auth) => format!("Basic {}", auth),
+        }
+    }
+}
+
+// The parts of npm's config qnpm understands, read from the user's and the
+// project's .npmrc
+#[derive(Debug, Default)]
+pub struct Npmrc {
+    pub registry: Option<String>,
+    // Scope (with its `@`) to the registry its packages come from
+    pub scope_registries: HashMap<String, String>,
+    // Per registry settings, keyed by the registry URL without its protocol
+    // (`//host/path/`), the way npm stores them
+    pub credentials: HashMap<String, Credentials>,
+    pub always_auth: bool,
+    pub always_auth_registries: Vec<String>,
+}
+
+impl Npmrc {
+    // Project settings override user settings
+    pub fn load(project_dir: &Path) -> Result<Self, NpmrcError> {
+        let mut npmrc = Self::default();
+        let mut files = Vec::new();
+        if let Some(home) = dirs::home_dir() {
+            files.push(home.join(".npmrc"));
+        }
+        files.push(project_dir.join(".npmrc"));
+        for path in files {
+            if !path.exists() {
+                continue;
+            }
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|e| NpmrcError::ReadFailed(path.clone(), e))?;
+            npmrc.parse(&contents, &path)?;
+        }
+        Ok(npmrc)
+    }
+
+    fn parse(&mut self, contents: &str, path: &Path) -> Result<(), NpmrcError> {
+        // Basic auth can be split over two keys, so it's assembled after parsing
+        let mut usernames: HashMap<String, String> = HashMap::new();
+        let mut passwords: HashMap<String, String> = HashMap::new();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let key = key.trim();
+            let value = interpolate_env(unquote(value.trim()), path)?;
+
+            if let Some((registry, setting)) = key.strip_prefix("//").and_then(|key| key.rsplit_once(":")) {
+                let registry = normalize_nerf_dart(&format!("//{}", registry));
+                match setting {
+                    "_authToken" => {
+                        self.credentials.insert(registry, Credentials::Token(value));
+                    }
+                    "_auth" => {
+                        self.credentials.insert(registry, Credentials::Basic(value));
+                    }
+                    "username" => {
+                        usernames.insert(registry, value);
+                    }
+                    "_password" => {
+                        passwords.insert(registry, value);
+                    }
+                    "always-auth" if value == "true" => self.always_auth_registries.push(registry),
+                    _ => {}
+                }
+            } else if let Some(scope) = key.strip_suffix(":registry") {
+                self.scope_registries.insert(scope.to_string(), value);
+            } else if key == "registry" {
+                self.registry = Some(value);
+            } else if key == "always-auth" {
+                self.always_auth = value == "true";
+            }
+        }
+
+        for (registry, username) in usernames {
+            // `_password` is stored base64 encoded, basic auth needs it decoded
+            let password = passwords
+                .get(&registry)
+                .and_then(|password| STANDARD.decode(password).ok())
+                .map(|password| String::from_utf8_lossy(&password).into_owned())
+                .unwrap_or_default();
+            let auth = STANDARD.encode(format!("{}:{}", username, password));
+            self.credentials.insert(registry, Credentials::Basic(auth));
+        }
+        Ok(())
+    }
+}
+
+// Strips the protocol off a registry URL, `https://host/path` becomes
+// `//host/path/`
+pub fn nerf_dart(url: &str) -> String {
+    let without_protocol = match url.find("//") {
+        Some(position) => &url[position..],
+        None => url,
+    };
+    normalize_nerf_dart(without_protocol)
+}
+
+fn normalize_nerf_dart(registry: &str) -> String {
+    if registry.ends_with('/') {
+        registry.to_string()
+    } else {
+        format!("{}/", registry)
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+// Replaces `${NAME}` with the environment variable, `${NAME?}` allows it to
+// be unset
+fn interpolate_env(value: &str, path: &Path) -> Result<String, NpmrcError> {
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let name = &rest[start + 2..start + end];
+        let (name, optional) = match name.strip_suffix('?') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        match std::env::var(name) {
+            Ok(value) => result.push_str(&value),
+            Err(_) if optional => {}
+            Err(_) => return Err(NpmrcError::MissingEnv(path.to_path_buf(), name.to_string())),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
@@ -0,0 +1,184 @@
+use reqwest::header::{ACCEPT, AUTHORIZATION, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::config::{Config, NetworkMode, DEFAULT_REGISTRY};
+use crate::memo::Memo;
+use crate::metadata_cache::{CachedPackument, MetadataCache};
+use crate::store::PackageIndex;
+use crate::npmrc::{nerf_dart, Credentials, Npmrc};
+
+pub struct Registry {
+    base_url: String,
+    npmrc: Npmrc,
+    // One client for the whole run so connections (and TLS sessions) are
+    // reused across every metadata and tarball request
+    client: Client,
+    // Caps how many requests are in flight at once
+    permits: Semaphore,
+    // Packuments by package name and store indexes by `name@version`, so
+    // packages shared by many dependents are only fetched once per run
+    pub packuments: Memo<Arc<Value>>,
+    // Packuments fetched again because the cached copy prefer-offline
+    // started from had no matching version
+    pub refreshed_packuments: Memo<Arc<Value>>,
+    pub downloads: Memo<Arc<PackageIndex>>,
+    pub metadata_cache: MetadataCache,
+    pub network_mode: NetworkMode,
+}
+
+// The abbreviated packument only carries what installing needs, and is a
+// fraction of the size of the full document
+const ABBREVIATED_PACKUMENT: &str = "application/vnd.npm.install-v1+json; q=1.0, application/json; q=0.8, */*";
+
+pub enum Revalidation {
+    NotModified,
+    Modified {
+        body: bytes::Bytes,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    },
+}
+
+fn with_trailing_slash(url: &str) -> String {
+    let url = url.trim();
+    if url.ends_with('/') {
+        url.to_string()
+    } else {
+        format!("{}/", url)
+    }
+}
+
+impl Registry {
+    pub fn new(base_url: &str, npmrc: Npmrc, config: &Config) -> Result<Self, reqwest::Error> {
+        let client = Client::builder()
+            .user_agent(concat!("qnpm/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(config.fetch_timeout))
+            .connect_timeout(Duration::from_secs(config.fetch_timeout.min(10)))
+            .pool_max_idle_per_host(config.network_concurrency)
+            .tcp_keepalive(Duration::from_secs(60))
+            .build()?;
+        Ok(Self {
+            base_url: with_trailing_slash(base_url),
+            npmrc,
+            client,
+            permits: Semaphore::new(config.network_concurrency.max(1)),
+            packuments: Memo::new(),
+            refreshed_packuments: Memo::new(),
+            downloads: Memo::new(),
+            metadata_cache: MetadataCache::new(&config.cache_dir, Duration::from_secs(config.metadata_ttl)),
+            network_mode: config.network_mode,
+        })
+    }
+
+    // Scoped packages can come from their own registry (`@scope:registry=`)
+    pub fn registry_for(&self, package_name: &str) -> String {
+        package_name
+            .split_once('/')
+            .filter(|(scope, _)| scope.starts_with('@'))
+            .and_then(|(scope, _)| self.npmrc.scope_registries.get(scope))
+            .map(|registry| with_trailing_slash(registry))
+            .unwrap_or_else(|| self.base_url.clone())
+    }
+
+    // The slash of a scoped name is encoded, registries expect `@scope%2fname`
+    pub fn packument_url(&self, package_name: &str) -> String {
+        format!("{}{}", self.registry_for(package_name), package_name.replacen('/', "%2f", 1))
+    }
+
+    // Packuments and lockfiles reference tarballs on the public registry. When
+    // a mirror is configured those downloads are sent to the mirror instead.
+    pub fn tarball_url(&self, package_name: &str, resolved: &str) -> String {
+        let registry = self.registry_for(package_name);
+        match resolved.strip_prefix(DEFAULT_REGISTRY) {
+            Some(path) if registry != DEFAULT_REGISTRY => format!("{}{}", registry, path),
+            _ => resolved.to_string(),
+        }
+    }
+
+    // Downloads one of `package_name`'s documents or tarballs. Waits for a
+    // free slot first, which is held until the whole body has arrived.
+    pub async fn fetch(&self, url: &str, package_name: &str) -> Result<bytes::Bytes, reqwest::Error> {
+        let _permit = self.permits.acquire().await.expect("registry semaphore is never closed");
+        self.get(url, package_name)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await
+    }
+
+    // Fetches a packument, sending the validators of the cached copy so the
+    // registry can answer with a body-less 304 when nothing changed
+    pub async fn fetch_packument(
+        &self,
+        url: &str,
+        package_name: &str,
+        cached: Option<&CachedPackument>,
+    ) -> Result<Revalidation, reqwest::Error> {
+        let _permit = self.permits.acquire().await.expect("registry semaphore is never closed");
+        let mut request = self.get(url, package_name).header(ACCEPT, ABBREVIATED_PACKUMENT);
+        if let Some(etag) = cached.and_then(|cached| cached.etag.as_deref()) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = cached.and_then(|cached| cached.last_modified.as_deref()) {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED && cached.is_some() {
+            return Ok(Revalidation::NotModified);
+        }
+        let response = response.error_for_status()?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        Ok(Revalidation::Modified {
+            body: response.bytes().await?,
+            etag,
+            last_modified,
+        })
+    }
+
+    // Builds a GET request with the credentials configured for the URL attached
+    fn get(&self, url: &str, package_name: &str) -> RequestBuilder {
+        let request = self.client.get(url);
+        match self.credentials_for(url, package_name) {
+            Some(credentials) => request.header(AUTHORIZATION, credentials.header_value()),
+            None => request,
+        }
+    }
+
+    fn credentials_for(&self, url: &str, package_name: &str) -> Option<&Credentials> {
+        let url = nerf_dart(url);
+        let matching = self
+            .npmrc
+            .credentials
+            .iter()
+            .filter(|(registry, _)| url.starts_with(registry.as_str()))
+            .max_by_key(|(registry, _)| registry.len())
+            .map(|(_, credentials)| credentials);
+        if matching.is_some() {
+            return matching;
+        }
+
+        // Tarballs are sometimes served from another host than the registry,
+        // `always-auth` sends the registry's credentials there too
+        let registry = nerf_dart(&self.registry_for(package_name));
+        let always_auth = self.npmrc.always_auth || self.npmrc.always_auth_registries.contains(&registry);
+        if always_auth {
+            self.npmrc.credentials.get(&registry)
+        } else {
+            None
+        }
+    }
+}
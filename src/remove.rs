@@ -1,27 +1,26 @@
 use std::error::Error;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::Path;
+use crate::add::DEPENDENCY_FIELDS;
 
 
-pub fn remove(package_name: &str, current_dir: &PathBuf) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let node_modules = current_dir.join("node_modules");
-    let package_dir = node_modules.join(package_name);
-    if package_dir.exists() {
-        std::fs::remove_dir_all(package_dir)?;
-    }
+// Only package.json changes here, the install that follows drops the package
+// from the lockfile and node_modules, wherever the layout put it
+pub fn remove(package_name: &str, current_dir: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
     remove_from_package_json(package_name, current_dir)?;
     Ok(())
 }
 
-fn remove_from_package_json(package_name: &str, current_dir: &PathBuf) -> Result<(), Box<dyn Error + Send + Sync>> {
+fn remove_from_package_json(package_name: &str, current_dir: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
     let package_json = current_dir.join("package.json");
     let package_json_str = std::fs::read_to_string(&package_json)?;
-    let package_json_value: serde_json::Value = serde_json::from_str(&package_json_str)?;
-    let dependencies = package_json_value["dependencies"].as_object().unwrap();
-    if dependencies.contains_key(package_name) {
-        let mut package_json_value = package_json_value.clone();
-        let dependencies = package_json_value["dependencies"].as_object_mut().unwrap();
-        dependencies.remove(package_name);
+    let mut package_json_value: serde_json::Value = serde_json::from_str(&package_json_str)?;
+    let mut removed = false;
+    for field in DEPENDENCY_FIELDS {
+        if let Some(dependencies) = package_json_value.get_mut(field).and_then(serde_json::Value::as_object_mut) {
+            removed |= dependencies.remove(package_name).is_some();
+        }
+    }
+    if removed {
         let package_json_str = serde_json::to_string_pretty(&package_json_value)?;
         std::fs::write(&package_json, package_json_str)?;
     }
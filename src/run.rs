@@ -1,18 +1,261 @@
+use serde_json::Value;
 use std::error::Error;
-use std::path::PathBuf;
-use std::process::Command;
-
-//run command getting  scripts and running the script associated with arg and running it using node
-pub fn run_script(packagejsonpath: &PathBuf, scriptname: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
-    //read package.json and get scripts
-    let packagejson = std::fs::read_to_string(packagejsonpath)?;
-    let packagejson: serde_json::Value = serde_json::from_str(&packagejson)?;
-    let scripts = packagejson["scripts"].as_object().unwrap();
-    let script = scripts.get(scriptname).unwrap().as_str().unwrap();
-    let node = Command::new("node")
-        .arg("-e")
-        .arg(script)
-        .output()?;
-    println!("{}", String::from_utf8_lossy(&node.stdout));
-    Ok(())
-}
\ No newline at end of file
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use thiserror::Error;
+
+use crate::config::{Config, NetworkMode};
+
+// Runs a script line through the configured shell, or the platform's like npm
+// does
+pub fn shell_command(script: &str, script_shell: Option<&str>) -> Command {
+    let default_shell = if cfg!(windows) { "cmd" } else { "sh" };
+    let shell = script_shell.unwrap_or(default_shell);
+    let mut command = Command::new(shell);
+    let is_cmd = Path::new(shell)
+        .file_stem()
+        .is_some_and(|stem| stem.eq_ignore_ascii_case("cmd"));
+    if is_cmd {
+        command.args(["/d", "/s", "/c", script]);
+    } else {
+        command.args(["-c", script]);
+    }
+    command
+}
+
+// A script of a package set up the way npm runs it, from the package's
+// directory with the commands of its dependencies on PATH and the npm_*
+// variables describing the package and the config
+pub fn script_command(
+    script: &str,
+    event: &str,
+    manifest: &Value,
+    package_dir: &Path,
+    init_cwd: &Path,
+    config: &Config,
+) -> Command {
+    let mut command = shell_command(script, config.script_shell.as_deref());
+    command
+        .current_dir(package_dir)
+        .env("PATH", bin_path(package_dir))
+        .env("INIT_CWD", init_cwd)
+        .env("npm_lifecycle_event", event)
+        .env("npm_lifecycle_script", script)
+        .env("npm_package_json", package_dir.join("package.json"))
+        .envs(package_env(manifest))
+        .envs(config_env(config));
+    if let Ok(exe) = std::env::current_exe() {
+        command.env("npm_execpath", exe);
+    }
+    if let Some(node) = find_in_path("node") {
+        command.env("npm_node_execpath", &node).env("NODE", node);
+    }
+    command
+}
+
+// The package.json fields npm exposes, `npm_package_name`,
+// `npm_package_version` and the entries of `config`, `engines` and `bin` as
+// `npm_package_config_<key>` and so on
+fn package_env(manifest: &Value) -> Vec<(String, String)> {
+    let mut env = Vec::new();
+    for field in ["name", "version"] {
+        env.push((format!("npm_package_{}", field), manifest[field].as_str().unwrap_or_default().to_string()));
+    }
+    for field in ["config", "engines", "bin"] {
+        if let Some(entries) = manifest[field].as_object() {
+            for (key, value) in entries {
+                flatten_value(&format!("npm_package_{}_{}", field, env_name(key)), value, &mut env);
+            }
+        }
+    }
+    env
+}
+
+fn flatten_value(prefix: &str, value: &Value, env: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(entries) => {
+            for (key, value) in entries {
+                flatten_value(&format!("{}_{}", prefix, env_name(key)), value, env);
+            }
+        }
+        Value::Array(items) => {
+            for (index, value) in items.iter().enumerate() {
+                flatten_value(&format!("{}_{}", prefix, index), value, env);
+            }
+        }
+        Value::String(value) => env.push((prefix.to_string(), value.clone())),
+        Value::Null => env.push((prefix.to_string(), String::new())),
+        value => env.push((prefix.to_string(), value.to_string())),
+    }
+}
+
+// Keys can contain characters environment variable names can't
+fn env_name(key: &str) -> String {
+    key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+// The effective config under the npm_config_* names npm uses for it
+fn config_env(config: &Config) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("npm_config_registry", config.registry.clone()),
+        ("npm_config_cache", config.cache_dir.display().to_string()),
+        ("npm_config_network_concurrency", config.network_concurrency.to_string()),
+        ("npm_config_fetch_timeout", config.fetch_timeout.to_string()),
+        ("npm_config_package_import_method", config.package_import_method.as_str().to_string()),
+        ("npm_config_node_linker", config.node_linker.as_str().to_string()),
+        ("npm_config_workspace_concurrency", config.workspace_concurrency.to_string()),
+        ("npm_config_ignore_scripts", config.ignore_scripts.to_string()),
+        ("npm_config_offline", (config.network_mode == NetworkMode::Offline).to_string()),
+        ("npm_config_prefer_offline", (config.network_mode == NetworkMode::PreferOffline).to_string()),
+        ("npm_config_user_agent", concat!("qnpm/", env!("CARGO_PKG_VERSION")).to_string()),
+    ];
+    if config.production {
+        env.push(("npm_config_omit", "dev".to_string()));
+    }
+    if let Some(script_shell) = &config.script_shell {
+        env.push(("npm_config_script_shell", script_shell.clone()));
+    }
+    env
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let file_name = if cfg!(windows) { format!("{}.exe", program) } else { program.to_string() };
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(&file_name))
+        .find(|path| path.is_file())
+}
+
+// PATH with the node_modules/.bin of the package and every directory above it
+// in front, so scripts find the commands of their dependencies
+pub fn bin_path(package_dir: &Path) -> OsString {
+    let bins = package_dir.ancestors().map(|dir| dir.join("node_modules").join(".bin"));
+    let existing = std::env::var_os("PATH").unwrap_or_default();
+    let paths: Vec<PathBuf> = bins.chain(std::env::split_paths(&existing)).collect();
+    std::env::join_paths(paths).unwrap_or(existing)
+}
+
+#[derive(Debug, Error)]
+pub enum RunError {
+    #[error("Missing script: \"{name}\"{}", available_scripts(.scripts))]
+    MissingScript { name: String, scripts: Vec<(String, String)> },
+}
+
+fn available_scripts(scripts: &[(String, String)]) -> String {
+    if scripts.is_empty() {
+        return "\nThe project has no scripts".to_string();
+    }
+    let mut list = String::from("\n\nAvailable scripts:");
+    for (name, script) in scripts {
+        list.push_str(&format!("\n  {}\n    {}", name, script));
+    }
+    list
+}
+
+// Runs a script from the project's package.json with the terminal attached,
+// along with its `pre` and `post` scripts, and returns the first non-zero exit
+// code. `args` are appended to the script itself, not to its hooks.
+pub fn run_script(
+    current_dir: &Path,
+    script_name: &str,
+    args: &[String],
+    config: &Config,
+) -> Result<i32, Box<dyn Error + Send + Sync>> {
+    let manifest: Value = serde_json::from_str(&std::fs::read_to_string(current_dir.join("package.json"))?)?;
+    let scripts = &manifest["scripts"];
+    if scripts[script_name].as_str().is_none() {
+        let scripts = scripts
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, script)| Some((name.clone(), script.as_str()?.to_string())))
+            .collect();
+        return Err(RunError::MissingScript {
+            name: script_name.to_string(),
+            scripts,
+        }
+        .into());
+    }
+
+    let package = package_label(&manifest, current_dir);
+    for (event, script) in script_steps(&manifest, script_name, args, config) {
+        println!("> {} {}\n> {}\n", package, event, script);
+        let status = script_command(&script, &event, &manifest, current_dir, current_dir, config)
+            .status()?;
+        let code = exit_code(status);
+        if code != 0 {
+            return Ok(code);
+        }
+    }
+    Ok(0)
+}
+
+// `name@version` of a package for the lines announcing its scripts
+pub fn package_label(manifest: &Value, package_dir: &Path) -> String {
+    match (manifest["name"].as_str(), manifest["version"].as_str()) {
+        (Some(name), Some(version)) => format!("{}@{}", name, version),
+        (Some(name), None) => name.to_string(),
+        _ => package_dir.display().to_string(),
+    }
+}
+
+// The scripts running `script_name` takes as event and script pairs, its `pre`
+// and `post` scripts around it. `args` are appended to the script itself, not
+// to its hooks.
+pub fn script_steps(manifest: &Value, script_name: &str, args: &[String], config: &Config) -> Vec<(String, String)> {
+    // --ignore-scripts skips the hooks but still runs what was asked for
+    let events = if config.ignore_scripts {
+        vec![script_name.to_string()]
+    } else {
+        vec![format!("pre{}", script_name), script_name.to_string(), format!("post{}", script_name)]
+    };
+    events
+        .into_iter()
+        .filter_map(|event| {
+            let script = manifest["scripts"][&event].as_str()?;
+            let script = if event == script_name && !args.is_empty() {
+                format!("{} {}", script, quote_args(args))
+            } else {
+                script.to_string()
+            };
+            Some((event, script))
+        })
+        .collect()
+}
+
+// A script killed by a signal has no exit code, shells report those as 128 + signal
+pub fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(1)
+}
+
+// Quotes arguments so the shell hands them to the script unchanged
+pub fn quote_args(args: &[String]) -> String {
+    args.iter().map(|arg| quote_arg(arg)).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(not(windows))]
+fn quote_arg(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(windows)]
+fn quote_arg(arg: &str) -> String {
+    let plain = !arg.is_empty() && !arg.contains([' ', '\t', '"', '&', '|', '<', '>', '^', '(', ')']);
+    if plain {
+        arg.to_string()
+    } else {
+        format!("\"{}\"", arg.replace('"', "\\\""))
+    }
+}
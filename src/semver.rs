@@ -0,0 +1,623 @@
+use std::cmp::Ordering;
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SemverError {
+    #[error("Invalid version '{0}'")]
+    InvalidVersion(String),
+    #[error("Invalid version range '{0}'")]
+    InvalidRange(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identifier {
+    Numeric(u64),
+    AlphaNumeric(String),
+}
+
+impl Ord for Identifier {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Identifier::Numeric(a), Identifier::Numeric(b)) => a.cmp(b),
+            // Numeric identifiers always have lower precedence than alphanumeric ones
+            (Identifier::Numeric(_), Identifier::AlphaNumeric(_)) => Ordering::Less,
+            (Identifier::AlphaNumeric(_), Identifier::Numeric(_)) => Ordering::Greater,
+            (Identifier::AlphaNumeric(a), Identifier::AlphaNumeric(b)) => a.cmp(b),
+        }
+    }
+}
+
+impl PartialOrd for Identifier {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identifier::Numeric(n) => write!(f, "{}", n),
+            Identifier::AlphaNumeric(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub prerelease: Vec<Identifier>,
+    pub build: Vec<String>,
+}
+
+impl Version {
+    pub fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+            prerelease: Vec::new(),
+            build: Vec::new(),
+        }
+    }
+
+    // Parses a version the way node-semver does in loose mode, so a leading
+    // `v` or `=` is accepted
+    pub fn parse(input: &str) -> Result<Self, SemverError> {
+        let invalid = || SemverError::InvalidVersion(input.to_string());
+        let trimmed = strip_version_prefix(input.trim());
+
+        let (rest, build) = match trimmed.split_once('+') {
+            Some((rest, build)) => (rest, parse_build(build).ok_or_else(invalid)?),
+            None => (trimmed, Vec::new()),
+        };
+        let (core, prerelease) = match rest.split_once('-') {
+            Some((core, pre)) => (core, parse_prerelease(pre).ok_or_else(invalid)?),
+            None => (rest, Vec::new()),
+        };
+
+        let parts: Vec<&str> = core.split('.').collect();
+        if parts.len() != 3 {
+            return Err(invalid());
+        }
+        let numbers: Vec<u64> = parts
+            .iter()
+            .map(|part| parse_number(part))
+            .collect::<Option<_>>()
+            .ok_or_else(invalid)?;
+
+        Ok(Self {
+            major: numbers[0],
+            minor: numbers[1],
+            patch: numbers[2],
+            prerelease,
+            build,
+        })
+    }
+
+    pub fn is_prerelease(&self) -> bool {
+        !self.prerelease.is_empty()
+    }
+
+    fn same_release(&self, other: &Version) -> bool {
+        self.major == other.major && self.minor == other.minor && self.patch == other.patch
+    }
+
+    // The lowest possible prerelease of a release, used as an exclusive upper
+    // bound so `<2.0.0-0` also keeps out prereleases of 2.0.0
+    fn lowest(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            prerelease: vec![Identifier::Numeric(0)],
+            ..Self::new(major, minor, patch)
+        }
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.major
+            .cmp(&other.major)
+            .then(self.minor.cmp(&other.minor))
+            .then(self.patch.cmp(&other.patch))
+            .then_with(|| match (self.prerelease.is_empty(), other.prerelease.is_empty()) {
+                // A release has higher precedence than any of its prereleases
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self.prerelease.cmp(&other.prerelease),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Build metadata is ignored when comparing versions
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.prerelease.is_empty() {
+            let pre: Vec<String> = self.prerelease.iter().map(|id| id.to_string()).collect();
+            write!(f, "-{}", pre.join("."))?;
+        }
+        if !self.build.is_empty() {
+            write!(f, "+{}", self.build.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    Eq,
+}
+
+#[derive(Debug, Clone)]
+struct Comparator {
+    op: Op,
+    version: Version,
+}
+
+impl Comparator {
+    fn new(op: Op, version: Version) -> Self {
+        Self { op, version }
+    }
+
+    fn any() -> Self {
+        Self::new(Op::Gte, Version::new(0, 0, 0))
+    }
+
+    fn none() -> Self {
+        Self::new(Op::Lt, Version::lowest(0, 0, 0))
+    }
+
+    fn matches(&self, version: &Version) -> bool {
+        let ordering = version.cmp(&self.version);
+        match self.op {
+            Op::Lt => ordering == Ordering::Less,
+            Op::Lte => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Gte => ordering != Ordering::Less,
+            Op::Eq => ordering == Ordering::Equal,
+        }
+    }
+}
+
+// A version where any of the trailing components may be missing or an x-range
+// wildcard (`x`, `X`, `*`)
+#[derive(Debug, Clone)]
+struct Partial {
+    major: Option<u64>,
+    minor: Option<u64>,
+    patch: Option<u64>,
+    prerelease: Vec<Identifier>,
+}
+
+impl Partial {
+    fn parse(input: &str) -> Option<Self> {
+        let trimmed = strip_version_prefix(input);
+        let rest = trimmed.split_once('+').map_or(trimmed, |(rest, _)| rest);
+        let (core, prerelease) = match rest.split_once('-') {
+            Some((core, pre)) => (core, parse_prerelease(pre)?),
+            None => (rest, Vec::new()),
+        };
+        if core.is_empty() {
+            return Some(Self { major: None, minor: None, patch: None, prerelease: Vec::new() });
+        }
+
+        let parts: Vec<&str> = core.split('.').collect();
+        if parts.len() > 3 {
+            return None;
+        }
+        let mut numbers = [None; 3];
+        for (i, part) in parts.iter().enumerate() {
+            if matches!(*part, "x" | "X" | "*") {
+                break;
+            }
+            numbers[i] = Some(parse_number(part)?);
+        }
+        // Anything after a wildcard is a wildcard too, `1.x.3` means `1.x`
+        let major = numbers[0];
+        let minor = major.and(numbers[1]);
+        let patch = minor.and(numbers[2]);
+        // Prerelease tags only mean something on a full version
+        let prerelease = if patch.is_some() { prerelease } else { Vec::new() };
+        Some(Self { major, minor, patch, prerelease })
+    }
+
+    fn floor(&self) -> Version {
+        Version {
+            prerelease: self.prerelease.clone(),
+            ..Version::new(
+                self.major.unwrap_or(0),
+                self.minor.unwrap_or(0),
+                self.patch.unwrap_or(0),
+            )
+        }
+    }
+
+    // Exclusive upper bound of the range a partial version covers, or `None`
+    // when the version is complete
+    fn ceiling(&self) -> Option<Version> {
+        match (self.major, self.minor, self.patch) {
+            (Some(major), None, _) => Some(Version::lowest(major + 1, 0, 0)),
+            (Some(major), Some(minor), None) => Some(Version::lowest(major, minor + 1, 0)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Range {
+    // Sets of comparators joined by `||`, a version has to satisfy every
+    // comparator of at least one set
+    sets: Vec<Vec<Comparator>>,
+}
+
+impl Range {
+    pub fn parse(input: &str) -> Result<Self, SemverError> {
+        let invalid = || SemverError::InvalidRange(input.to_string());
+        let sets = input
+            .split("||")
+            .map(|set| parse_comparator_set(set.trim()).ok_or_else(invalid))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { sets })
+    }
+
+    pub fn satisfies(&self, version: &Version) -> bool {
+        self.sets.iter().any(|set| set_satisfies(set, version))
+    }
+
+    pub fn max_satisfying<'a, I>(&self, versions: I) -> Option<Version>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        versions
+            .into_iter()
+            .filter_map(|version| Version::parse(version).ok())
+            .filter(|version| self.satisfies(version))
+            .max()
+    }
+}
+
+fn set_satisfies(set: &[Comparator], version: &Version) -> bool {
+    if !set.iter().all(|comparator| comparator.matches(version)) {
+        return false;
+    }
+    if !version.is_prerelease() {
+        return true;
+    }
+    // Prereleases only satisfy a range if one of its comparators opts into
+    // prereleases of that exact release, so `^1.2.3-beta` allows
+    // `1.2.3-rc.1` but never `1.3.0-beta`
+    set.iter().any(|comparator| {
+        comparator.version.is_prerelease() && comparator.version.same_release(version)
+    })
+}
+
+fn parse_comparator_set(input: &str) -> Option<Vec<Comparator>> {
+    if input.is_empty() {
+        return Some(vec![Comparator::any()]);
+    }
+    if let Some((from, to)) = input.split_once(" - ") {
+        return parse_hyphen_range(from.trim(), to.trim());
+    }
+
+    // Operators may be separated from their version by whitespace (`>= 1.2.3`)
+    let mut tokens: Vec<String> = Vec::new();
+    let mut pending_op: Option<&str> = None;
+    for token in input.split_whitespace() {
+        if is_operator(token) {
+            pending_op = Some(token);
+            continue;
+        }
+        match pending_op.take() {
+            Some(op) => tokens.push(format!("{}{}", op, token)),
+            None => tokens.push(token.to_string()),
+        }
+    }
+    if pending_op.is_some() {
+        return None;
+    }
+
+    let mut comparators = Vec::new();
+    for token in tokens {
+        comparators.extend(parse_primitive(&token)?);
+    }
+    Some(comparators)
+}
+
+fn is_operator(token: &str) -> bool {
+    matches!(token, "<" | "<=" | ">" | ">=" | "=" | "~" | "~>" | "^")
+}
+
+fn parse_hyphen_range(from: &str, to: &str) -> Option<Vec<Comparator>> {
+    let from = Partial::parse(from)?;
+    let to = Partial::parse(to)?;
+    let mut comparators = Vec::new();
+    if from.major.is_some() {
+        comparators.push(Comparator::new(Op::Gte, from.floor()));
+    }
+    if to.major.is_some() {
+        match to.ceiling() {
+            Some(ceiling) => comparators.push(Comparator::new(Op::Lt, ceiling)),
+            None => comparators.push(Comparator::new(Op::Lte, to.floor())),
+        }
+    }
+    if comparators.is_empty() {
+        comparators.push(Comparator::any());
+    }
+    Some(comparators)
+}
+
+fn parse_primitive(token: &str) -> Option<Vec<Comparator>> {
+    let (op, rest) = ["~>", ">=", "<=", "~", "^", ">", "<", "="]
+        .iter()
+        .find_map(|op| token.strip_prefix(op).map(|rest| (*op, rest)))
+        .unwrap_or(("", token));
+    let partial = Partial::parse(rest)?;
+
+    let Some(major) = partial.major else {
+        // A bare wildcard matches everything, unless it's used as a strict bound
+        return Some(vec![match op {
+            ">" | "<" => Comparator::none(),
+            _ => Comparator::any(),
+        }]);
+    };
+
+    let comparators = match op {
+        "~" | "~>" => {
+            let ceiling = match partial.minor {
+                Some(minor) => Version::lowest(major, minor + 1, 0),
+                None => Version::lowest(major + 1, 0, 0),
+            };
+            vec![
+                Comparator::new(Op::Gte, partial.floor()),
+                Comparator::new(Op::Lt, ceiling),
+            ]
+        }
+        "^" => {
+            let ceiling = match (partial.minor, partial.patch) {
+                (Some(0), Some(patch)) if major == 0 => Version::lowest(0, 0, patch + 1),
+                (Some(minor), _) if major == 0 => Version::lowest(0, minor + 1, 0),
+                _ => Version::lowest(major + 1, 0, 0),
+            };
+            vec![
+                Comparator::new(Op::Gte, partial.floor()),
+                Comparator::new(Op::Lt, ceiling),
+            ]
+        }
+        ">" => match partial.ceiling() {
+            Some(ceiling) => vec![Comparator::new(Op::Gte, without_prerelease(ceiling))],
+            None => vec![Comparator::new(Op::Gt, partial.floor())],
+        },
+        ">=" => vec![Comparator::new(Op::Gte, partial.floor())],
+        "<" => match partial.ceiling() {
+            Some(_) => vec![Comparator::new(Op::Lt, Version::lowest(major, partial.minor.unwrap_or(0), 0))],
+            None => vec![Comparator::new(Op::Lt, partial.floor())],
+        },
+        "<=" => match partial.ceiling() {
+            Some(ceiling) => vec![Comparator::new(Op::Lt, ceiling)],
+            None => vec![Comparator::new(Op::Lte, partial.floor())],
+        },
+        _ => match partial.ceiling() {
+            Some(ceiling) => vec![
+                Comparator::new(Op::Gte, partial.floor()),
+                Comparator::new(Op::Lt, ceiling),
+            ],
+            None => vec![Comparator::new(Op::Eq, partial.floor())],
+        },
+    };
+    Some(comparators)
+}
+
+fn without_prerelease(version: Version) -> Version {
+    Version::new(version.major, version.minor, version.patch)
+}
+
+fn strip_version_prefix(input: &str) -> &str {
+    let input = input.trim_start_matches('=').trim_start();
+    input.strip_prefix('v').unwrap_or(input)
+}
+
+fn parse_number(input: &str) -> Option<u64> {
+    if input.is_empty() || !input.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    input.parse().ok()
+}
+
+fn is_identifier(input: &str) -> bool {
+    !input.is_empty() && input.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+fn parse_prerelease(input: &str) -> Option<Vec<Identifier>> {
+    input
+        .split('.')
+        .map(|id| {
+            if !is_identifier(id) {
+                None
+            } else if let Some(n) = parse_number(id) {
+                Some(Identifier::Numeric(n))
+            } else {
+                Some(Identifier::AlphaNumeric(id.to_string()))
+            }
+        })
+        .collect()
+}
+
+fn parse_build(input: &str) -> Option<Vec<String>> {
+    input
+        .split('.')
+        .map(|id| is_identifier(id).then(|| id.to_string()))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn satisfies(range: &str, version: &str) -> bool {
+        Range::parse(range).unwrap().satisfies(&Version::parse(version).unwrap())
+    }
+
+    // Every version has to satisfy the range
+    fn assert_matches(range: &str, versions: &[&str]) {
+        for version in versions {
+            assert!(satisfies(range, version), "{} should satisfy {}", version, range);
+        }
+    }
+
+    // And none of these may
+    fn assert_rejects(range: &str, versions: &[&str]) {
+        for version in versions {
+            assert!(!satisfies(range, version), "{} shouldn't satisfy {}", version, range);
+        }
+    }
+
+    #[test]
+    fn parses_loose_versions() {
+        let version = Version::parse("v1.2.3-beta.4+build.5").unwrap();
+        assert_eq!((version.major, version.minor, version.patch), (1, 2, 3));
+        assert_eq!(version.prerelease, [Identifier::AlphaNumeric("beta".to_string()), Identifier::Numeric(4)]);
+        assert_eq!(version.build, ["build", "5"]);
+        assert_eq!(Version::parse("=1.2.3").unwrap(), Version::new(1, 2, 3));
+        assert!(Version::parse("1.2").is_err());
+        assert!(Version::parse("1.2.x").is_err());
+        assert!(Version::parse("1.2.3-").is_err());
+    }
+
+    #[test]
+    fn orders_prereleases_before_their_release() {
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(Version::parse(pair[0]).unwrap() < Version::parse(pair[1]).unwrap(), "{} < {}", pair[0], pair[1]);
+        }
+        // Build metadata doesn't count
+        assert_eq!(Version::parse("1.0.0+a").unwrap(), Version::parse("1.0.0+b").unwrap());
+    }
+
+    #[test]
+    fn caret_ranges() {
+        assert_matches("^1.2.3", &["1.2.3", "1.9.0"]);
+        assert_rejects("^1.2.3", &["1.2.2", "2.0.0", "2.0.0-0"]);
+        assert_matches("^0.2.3", &["0.2.3", "0.2.9"]);
+        assert_rejects("^0.2.3", &["0.3.0"]);
+        assert_matches("^0.0.3", &["0.0.3"]);
+        assert_rejects("^0.0.3", &["0.0.4"]);
+        assert_matches("^0.0", &["0.0.9"]);
+        assert_rejects("^0.0", &["0.1.0"]);
+        assert_matches("^1.x", &["1.0.0", "1.9.9"]);
+        assert_rejects("^1.x", &["2.0.0"]);
+    }
+
+    #[test]
+    fn tilde_ranges() {
+        assert_matches("~1.2.3", &["1.2.3", "1.2.9"]);
+        assert_rejects("~1.2.3", &["1.3.0"]);
+        assert_matches("~1.2", &["1.2.0", "1.2.9"]);
+        assert_rejects("~1.2", &["1.3.0"]);
+        assert_matches("~1", &["1.0.0", "1.9.9"]);
+        assert_rejects("~1", &["2.0.0"]);
+        assert_matches("~0.2.3", &["0.2.5"]);
+        assert_rejects("~0.2.3", &["0.3.0"]);
+        assert_matches("~>1.2", &["1.2.4"]);
+    }
+
+    #[test]
+    fn x_ranges() {
+        assert_matches("*", &["0.0.1", "9.9.9"]);
+        assert_matches("", &["1.0.0"]);
+        assert_matches("1.x", &["1.0.0", "1.9.9"]);
+        assert_rejects("1.x", &["2.0.0", "0.9.9"]);
+        assert_matches("1.2.*", &["1.2.0", "1.2.9"]);
+        assert_rejects("1.2.*", &["1.3.0"]);
+        assert_matches("1", &["1.4.0"]);
+        assert_rejects("*", &["1.0.0-beta"]);
+    }
+
+    #[test]
+    fn comparator_ranges() {
+        assert_matches(">1.2", &["1.3.0"]);
+        assert_rejects(">1.2", &["1.2.9"]);
+        assert_matches("<=1.2", &["1.2.9"]);
+        assert_rejects("<=1.2", &["1.3.0"]);
+        assert_matches("<1.2", &["1.1.9"]);
+        assert_rejects("<1.2", &["1.2.0"]);
+        assert_matches(">=1.2.3 <2.0.0", &["1.2.3", "1.9.9"]);
+        assert_rejects(">=1.2.3 <2.0.0", &["2.0.0"]);
+        assert_matches(">= 1.2.3 < 2", &["1.5.0"]);
+        assert_rejects(">= 1.2.3 < 2", &["2.0.0"]);
+        assert_matches("=1.2.3", &["1.2.3"]);
+        assert_rejects("1.2.3", &["1.2.4"]);
+        assert_rejects(">*", &["1.0.0"]);
+    }
+
+    #[test]
+    fn hyphen_ranges() {
+        assert_matches("1.2.3 - 2.3.4", &["1.2.3", "2.3.4"]);
+        assert_rejects("1.2.3 - 2.3.4", &["1.2.2", "2.3.5"]);
+        assert_matches("1.2 - 2.3", &["1.2.0", "2.3.9"]);
+        assert_rejects("1.2 - 2.3", &["2.4.0"]);
+        assert_matches("1.2.3 - 2", &["2.9.9"]);
+        assert_rejects("1.2.3 - 2", &["3.0.0"]);
+    }
+
+    #[test]
+    fn or_ranges() {
+        assert_matches("1.x || >=2.5.0 || 5.0.0 - 7.2.3", &["1.2.3", "2.5.0", "7.2.3"]);
+        assert_rejects("1.x || >=2.5.0 <3", &["2.4.9", "3.0.0"]);
+        assert_matches("^1.0.0 || ^3.0.0", &["3.1.0"]);
+    }
+
+    #[test]
+    fn prereleases_need_a_comparator_of_the_same_release() {
+        assert_matches(">1.2.3-alpha.3", &["1.2.3-alpha.7", "3.4.5"]);
+        assert_rejects(">1.2.3-alpha.3", &["3.4.5-alpha.9"]);
+        assert_matches("^1.2.3-beta.2", &["1.2.3-beta.4", "1.2.3"]);
+        assert_rejects("^1.2.3-beta.2", &["1.2.4-beta.2", "1.2.3-beta.1"]);
+        assert_rejects("^1.2.3", &["1.2.4-beta.1"]);
+    }
+
+    #[test]
+    fn max_satisfying_picks_the_highest_match() {
+        let versions = ["1.0.0", "1.2.0", "1.10.0", "2.0.0", "2.1.0-beta.1", "not-a-version"];
+        assert_eq!(Range::parse("^1.0.0").unwrap().max_satisfying(versions), Some(Version::new(1, 10, 0)));
+        assert_eq!(Range::parse(">=2").unwrap().max_satisfying(versions), Some(Version::new(2, 0, 0)));
+        assert_eq!(Range::parse("^3").unwrap().max_satisfying(versions), None);
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!(Range::parse(">=").is_err());
+        assert!(Range::parse("1.2.3.4").is_err());
+        assert!(Range::parse("^a.b.c").is_err());
+    }
+}
@@ -0,0 +1,270 @@
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha512};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, EntryType};
+
+use crate::add::{cache_key, package_path};
+use crate::bin_links::manifest_bins;
+use crate::config::{Config, ImportMethod, NodeLinker};
+use crate::link::remove_link;
+use crate::semver::Version;
+
+// Content-addressable package store shared by every project using the same
+// cache directory. File contents live once under `files/` keyed by their
+// sha512, and each package version has an index mapping its paths to them.
+#[derive(Clone)]
+pub struct Store {
+    dir: PathBuf,
+    import_method: ImportMethod,
+    pub node_linker: NodeLinker,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageIndex {
+    // The tarball integrity the package was verified against
+    pub integrity: String,
+    // Path inside the package to the file it is made of
+    pub files: BTreeMap<String, StoredFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredFile {
+    // Hex sha512 of the contents
+    pub hash: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub executable: bool,
+}
+
+impl Store {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            dir: config.cache_dir.join("store").join("v1"),
+            import_method: config.package_import_method,
+            node_linker: config.node_linker,
+        }
+    }
+
+    pub fn index_path(&self, package_name: &str, version: &str) -> PathBuf {
+        let mut path = package_path(&self.dir.join("index"), &cache_key(package_name, version)).into_os_string();
+        path.push(".json");
+        PathBuf::from(path)
+    }
+
+    // Hardlinked files share their permissions, so executables are stored
+    // separately from identical files that aren't
+    fn file_path(&self, file: &StoredFile) -> PathBuf {
+        let (prefix, rest) = file.hash.split_at(2);
+        let name = if file.executable { format!("{}-exec", rest) } else { rest.to_string() };
+        self.dir.join("files").join(prefix).join(name)
+    }
+
+    pub fn load_index(&self, package_name: &str, version: &str) -> Option<PackageIndex> {
+        let contents = fs::read(self.index_path(package_name, version)).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    // Re-hashes the files a package is made of against the hashes naming them.
+    // Files that don't match are removed, so adding the package again
+    // replaces them instead of keeping the damaged copy.
+    pub fn verify_files(&self, index: &PackageIndex) -> bool {
+        let mut intact = true;
+        for file in index.files.values() {
+            let path = self.file_path(file);
+            let matches = fs::read(&path).is_ok_and(|contents| hex(&Sha512::digest(&contents)) == file.hash);
+            if !matches {
+                let _ = fs::remove_file(&path);
+                intact = false;
+            }
+        }
+        intact
+    }
+
+    pub fn remove_index(&self, package_name: &str, version: &str) -> io::Result<()> {
+        fs::remove_file(self.index_path(package_name, version))
+    }
+
+    // Removes the index of every stored version of a package
+    pub fn remove_indexes(&self, package_name: &str) -> io::Result<()> {
+        let scope_dir = package_path(&self.dir.join("index"), package_name);
+        let (Some(dir), Some(base_name)) = (scope_dir.parent(), scope_dir.file_name()) else {
+            return Ok(());
+        };
+        let prefix = format!("{}-", base_name.to_string_lossy());
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            let is_version = file_name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".json"))
+                .is_some_and(|version| Version::parse(version).is_ok());
+            if is_version {
+                println!("Removing package from the store: {:?}", path);
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Adds the files of a package tarball that aren't in the store yet and
+    // records the package's index
+    pub fn add_tarball(&self, package_name: &str, version: &str, tarball: &[u8], integrity: &str) -> io::Result<PackageIndex> {
+        let mut archive = Archive::new(GzDecoder::new(tarball));
+        let mut entries: Vec<(String, Vec<u8>, bool)> = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type() != EntryType::Regular {
+                continue;
+            }
+            // Tarballs wrap everything in a top-level directory (usually
+            // `package/`), which is dropped
+            let path = entry.path()?.into_owned();
+            let relative: PathBuf = path.components().skip(1).collect();
+            if relative.as_os_str().is_empty()
+                || !relative.components().all(|component| matches!(component, Component::Normal(_)))
+            {
+                continue;
+            }
+            let executable = entry.header().mode().is_ok_and(|mode| mode & 0o111 != 0);
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            entries.push((relative.to_string_lossy().replace('\\', "/"), contents, executable));
+        }
+
+        // Commands get linked into node_modules/.bin and have to be
+        // executable even when the tarball doesn't say so
+        let bins = entries
+            .iter()
+            .find(|(relative, _, _)| relative == "package.json")
+            .and_then(|(_, contents, _)| serde_json::from_slice::<Value>(contents).ok())
+            .map(|manifest| bin_files(&manifest, entries.iter().map(|(relative, _, _)| relative.as_str())))
+            .unwrap_or_default();
+
+        let mut files = BTreeMap::new();
+        for (relative, contents, executable) in entries {
+            let file = StoredFile {
+                hash: hex(&Sha512::digest(&contents)),
+                executable: executable || bins.contains(&relative),
+            };
+            let file_path = self.file_path(&file);
+            if !file_path.exists() {
+                write_atomically(&file_path, &contents)?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mode = if file.executable { 0o755 } else { 0o644 };
+                    fs::set_permissions(&file_path, fs::Permissions::from_mode(mode))?;
+                }
+            }
+            files.insert(relative, file);
+        }
+
+        let index = PackageIndex {
+            integrity: integrity.to_string(),
+            files,
+        };
+        let contents = serde_json::to_vec(&index).map_err(io::Error::other)?;
+        write_atomically(&self.index_path(package_name, version), &contents)?;
+        Ok(index)
+    }
+
+    // Recreates a package at `destination` from the store using the
+    // configured import method
+    pub fn import(&self, index: &PackageIndex, destination: &Path) -> io::Result<()> {
+        if fs::symlink_metadata(destination).is_ok() {
+            remove_link(destination)?;
+        }
+        fs::create_dir_all(destination)?;
+        for (relative, file) in &index.files {
+            let target = destination.join(relative);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            import_file(&self.file_path(file), &target, self.import_method)?;
+        }
+        Ok(())
+    }
+}
+
+// Gives every file of an imported package that is hardlinked from the store
+// its own copy, so a package can change its files, like install scripts do,
+// without changing them for every other project using the store. Nested
+// node_modules hold other packages and are left alone.
+pub fn unshare_files(package_dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(package_dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = entry.path();
+        if file_type.is_dir() && entry.file_name() != "node_modules" {
+            unshare_files(&path)?;
+        } else if file_type.is_file() && is_shared(&entry.metadata()?) {
+            let permissions = entry.metadata()?.permissions();
+            let contents = fs::read(&path)?;
+            write_atomically(&path, &contents)?;
+            fs::set_permissions(&path, permissions)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_shared(metadata: &fs::Metadata) -> bool {
+    std::os::unix::fs::MetadataExt::nlink(metadata) > 1
+}
+
+// Link counts aren't available on stable Rust elsewhere, so every file counts
+#[cfg(not(unix))]
+fn is_shared(_metadata: &fs::Metadata) -> bool {
+    true
+}
+
+// Paths of the files a manifest's commands point at, among the package's
+// `paths`
+fn bin_files<'a>(manifest: &Value, paths: impl Iterator<Item = &'a str>) -> BTreeSet<String> {
+    let directory_files = |directory: &str| {
+        let prefix = format!("{}/", directory);
+        paths
+            .filter_map(|path| path.strip_prefix(&prefix))
+            .filter(|name| !name.contains('/'))
+            .map(str::to_string)
+            .collect()
+    };
+    manifest_bins(manifest, directory_files).into_iter().map(|(_, path)| path).collect()
+}
+
+fn import_file(source: &Path, target: &Path, method: ImportMethod) -> io::Result<()> {
+    match method {
+        ImportMethod::Hardlink => fs::hard_link(source, target),
+        ImportMethod::Reflink => reflink_copy::reflink_or_copy(source, target).map(|_| ()),
+        ImportMethod::Copy => fs::copy(source, target).map(|_| ()),
+        // Reflinks cost no space and can't leak edits back into the store,
+        // hardlinks at least cost no space, copies always work
+        ImportMethod::Auto => reflink_copy::reflink(source, target)
+            .or_else(|_| fs::hard_link(source, target))
+            .or_else(|_| fs::copy(source, target).map(|_| ())),
+    }
+}
+
+// Writes through a temporary file in the same directory, so other processes
+// sharing the store never see a partially written file
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)?;
+    let mut file = tempfile::NamedTempFile::new_in(parent)?;
+    file.write_all(contents)?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
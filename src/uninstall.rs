@@ -1,57 +1,55 @@
 use std::error::Error;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::Path;
+use crate::add::DEPENDENCY_FIELDS;
+use crate::lockfile::Lockfile;
+use crate::store::Store;
+use crate::workspace::Project;
 
 
-pub fn uninstall(package_name: &str, current_dir: &PathBuf, cache_dir: &PathBuf) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let node_modules = current_dir.join("node_modules");
-    let package_dir = node_modules.join(package_name);
-    if package_dir.exists() {
-        std::fs::remove_dir_all(package_dir)?;
-    }
+// Like remove, but also drops the package from the store
+pub fn uninstall(package_name: &str, project: &Project, store: &Store) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let current_dir = project.dir.as_path();
     //get package version from package.json
     let package_json = current_dir.join("package.json");
     let package_json_str = std::fs::read_to_string(&package_json)?;
     let package_json_value: serde_json::Value = serde_json::from_str(&package_json_str)?;
-    let dependencies = package_json_value["dependencies"].as_object().unwrap();
-    //if package in package.json get version and remove from cache
-    if dependencies.contains_key(package_name) {
-        let package_version = dependencies[package_name].as_str().unwrap();
-        let package_cache_dir = cache_dir.join("node_modules").join(format!("{}-{}", package_name, package_version));
-        if package_cache_dir.exists() {
-            println!("Removing package cache: {:?}", package_cache_dir);
-            std::fs::remove_dir_all(package_cache_dir)?;
+    let spec = DEPENDENCY_FIELDS
+        .iter()
+        .find_map(|field| package_json_value[*field][package_name].as_str());
+    //if package in package.json drop the installed version from the store, otherwise every version
+    // The files themselves stay, other packages in the store may share them
+    if let Some(spec) = spec {
+        let locked_version = Lockfile::load(&project.root)
+            .ok()
+            .flatten()
+            .and_then(|lockfile| lockfile.importer(&project.importer)?.dependencies.get(package_name).cloned());
+        let package_version = locked_version.unwrap_or_else(|| spec.to_string());
+        let index_path = store.index_path(package_name, &package_version);
+        if index_path.exists() {
+            println!("Removing package from the store: {:?}", index_path);
+            store.remove_index(package_name, &package_version)?;
         }
     }
     else
     {
-        //Find folders, then check their names to see if it contains the package name
-        let cache_dir_contents = std::fs::read_dir(cache_dir)?;
-        for entry in cache_dir_contents {
-            let entry = entry?;
-            let entry_path = entry.path();
-            if entry_path.is_dir() {
-                let entry_name = entry_path.file_name().unwrap().to_str().unwrap();
-                if entry_name.contains(package_name) {
-                    std::fs::remove_dir_all(entry_path)?;
-                }
-            }
-        }
+        store.remove_indexes(package_name)?;
     }
 
     remove_from_package_json(package_name, current_dir)?;
     Ok(())
 }
 
-fn remove_from_package_json(package_name: &str, current_dir: &PathBuf) -> Result<(), Box<dyn Error + Send + Sync>> {
+fn remove_from_package_json(package_name: &str, current_dir: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
     let package_json = current_dir.join("package.json");
     let package_json_str = std::fs::read_to_string(&package_json)?;
-    let package_json_value: serde_json::Value = serde_json::from_str(&package_json_str)?;
-    let dependencies = package_json_value["dependencies"].as_object().unwrap();
-    if dependencies.contains_key(package_name) {
-        let mut package_json_value = package_json_value.clone();
-        let dependencies = package_json_value["dependencies"].as_object_mut().unwrap();
-        dependencies.remove(package_name);
+    let mut package_json_value: serde_json::Value = serde_json::from_str(&package_json_str)?;
+    let mut removed = false;
+    for field in DEPENDENCY_FIELDS {
+        if let Some(dependencies) = package_json_value.get_mut(field).and_then(serde_json::Value::as_object_mut) {
+            removed |= dependencies.remove(package_name).is_some();
+        }
+    }
+    if removed {
         let package_json_str = serde_json::to_string_pretty(&package_json_value)?;
         std::fs::write(&package_json, package_json_str)?;
     }
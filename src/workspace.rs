@@ -0,0 +1,315 @@
+use serde_json::Value;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+use crate::lockfile::ROOT_IMPORTER;
+use crate::semver::{Range, Version};
+
+#[derive(Debug, Error)]
+pub enum WorkspaceError {
+    #[error("Failed to read {0}: {1}")]
+    ReadFailed(PathBuf, std::io::Error),
+    #[error("Failed to parse {0}: {1}")]
+    ParseFailed(PathBuf, serde_json::Error),
+    #[error("Workspace member {0} has no name in its package.json")]
+    MissingName(String),
+    #[error("Workspace members {1} and {2} are both named {0}")]
+    DuplicateName(String, String, String),
+    #[error("No workspace member named or at {0}")]
+    NotFound(String),
+    #[error("No workspace member matches {0}")]
+    NoMatch(String),
+    #[error("{0}@{1} asks for a workspace member but there is none named {0}")]
+    NoSuchMember(String, String),
+    #[error("{0}@{1} doesn't match the workspace member's version {2}")]
+    VersionMismatch(String, String, String),
+}
+
+// A package of a monorepo, found through the `workspaces` field of the root
+// package.json
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    pub name: String,
+    pub version: String,
+    // Relative to the root with `/` separators, the member's key in the lockfile
+    pub path: String,
+    pub dir: PathBuf,
+}
+
+// Where a command runs: the root holding the lockfile and node_modules, and
+// the package whose package.json it edits, which is a workspace member when
+// run from inside one or given `--workspace`
+pub struct Project {
+    pub root: PathBuf,
+    pub dir: PathBuf,
+    // The importer `dir` is in the lockfile
+    pub importer: String,
+}
+
+// The members listed by the root package.json, either as `"workspaces": [..]`
+// or `"workspaces": { "packages": [..] }`. Patterns may use `*` and `**`, and
+// ones starting with `!` exclude what they match.
+pub fn find_workspaces(root: &Path) -> Result<Vec<Workspace>, WorkspaceError> {
+    let manifest = read_manifest(root)?;
+    let patterns = workspace_patterns(&manifest);
+
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    for pattern in patterns.iter().filter(|pattern| !pattern.starts_with('!')) {
+        for dir in expand_pattern(root, pattern) {
+            if dir.join("package.json").is_file() && !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+    for pattern in patterns.iter().filter_map(|pattern| pattern.strip_prefix('!')) {
+        let excluded = expand_pattern(root, pattern);
+        dirs.retain(|dir| !excluded.contains(dir));
+    }
+    dirs.sort();
+
+    let mut workspaces: Vec<Workspace> = Vec::new();
+    for dir in dirs {
+        let path = relative_key(root, &dir);
+        let manifest = read_manifest(&dir)?;
+        let Some(name) = manifest["name"].as_str() else {
+            return Err(WorkspaceError::MissingName(path));
+        };
+        if let Some(other) = workspaces.iter().find(|workspace| workspace.name == name) {
+            return Err(WorkspaceError::DuplicateName(name.to_string(), other.path.clone(), path));
+        }
+        workspaces.push(Workspace {
+            name: name.to_string(),
+            version: manifest["version"].as_str().unwrap_or("0.0.0").to_string(),
+            path,
+            dir,
+        });
+    }
+    Ok(workspaces)
+}
+
+// Finds the workspace root above `current_dir`, if it is inside one, and the
+// package the command is about. `workspace` picks a member by name or path.
+pub fn find_project(current_dir: &Path, workspace: Option<&str>) -> Result<Project, WorkspaceError> {
+    let root = current_dir
+        .ancestors()
+        .filter(|dir| dir.join("package.json").is_file())
+        .find(|dir| {
+            let Ok(manifest) = read_manifest(dir) else {
+                return false;
+            };
+            !workspace_patterns(&manifest).is_empty()
+                && (*dir == current_dir
+                    || find_workspaces(dir).is_ok_and(|members| {
+                        members.iter().any(|member| current_dir.starts_with(&member.dir))
+                    }))
+        })
+        .unwrap_or(current_dir)
+        .to_path_buf();
+
+    let members = if root.join("package.json").is_file() { find_workspaces(&root)? } else { Vec::new() };
+    let member = match workspace {
+        Some(wanted) => {
+            let wanted_path = wanted.trim_end_matches('/').trim_start_matches("./");
+            let member = members
+                .iter()
+                .find(|member| member.name == wanted || member.path == wanted_path);
+            Some(member.ok_or_else(|| WorkspaceError::NotFound(wanted.to_string()))?)
+        }
+        None => members.iter().find(|member| current_dir.starts_with(&member.dir)),
+    };
+    Ok(match member {
+        Some(member) => Project {
+            dir: member.dir.clone(),
+            importer: member.path.clone(),
+            root,
+        },
+        None => Project {
+            dir: root.clone(),
+            importer: ROOT_IMPORTER.to_string(),
+            root,
+        },
+    })
+}
+
+// The member a dependency of the root or another member refers to, if any.
+// `workspace:` specs must name a member, plain ranges link one when its
+// version satisfies them and come from the registry otherwise.
+pub fn find_linked<'a>(workspaces: &'a [Workspace], name: &str, spec: &str) -> Result<Option<&'a Workspace>, WorkspaceError> {
+    let member = workspaces.iter().find(|workspace| workspace.name == name);
+    let satisfies = |member: &Workspace, range: &str| {
+        let range = Range::parse(range);
+        let version = Version::parse(&member.version);
+        matches!((range, version), (Ok(range), Ok(version)) if range.satisfies(&version))
+    };
+    match spec.strip_prefix("workspace:") {
+        Some(range) => {
+            let member = member.ok_or_else(|| WorkspaceError::NoSuchMember(name.to_string(), spec.to_string()))?;
+            // `workspace:*`, `workspace:^` and `workspace:~` take whatever version it has
+            if ["*", "^", "~", ""].contains(&range) || satisfies(member, range) {
+                Ok(Some(member))
+            } else {
+                Err(WorkspaceError::VersionMismatch(name.to_string(), spec.to_string(), member.version.clone()))
+            }
+        }
+        None => Ok(member.filter(|member| satisfies(member, spec))),
+    }
+}
+
+// The members `--filter` patterns select. Patterns starting with `.` are
+// directory globs relative to the root like the ones in `workspaces`, others
+// match names, `*` matching any run of characters as in `@scope/*`. Patterns
+// starting with `!` drop what they match from the selection, which starts out
+// as every member when there are only those.
+pub fn filter_workspaces(root: &Path, workspaces: &[Workspace], filters: &[String]) -> Result<Vec<Workspace>, WorkspaceError> {
+    let matches = |member: &Workspace, pattern: &str| {
+        if pattern.starts_with('.') {
+            expand_pattern(root, pattern).contains(&member.dir)
+        } else {
+            matches_segment(pattern, &member.name)
+        }
+    };
+    let (excluding, including): (Vec<&str>, Vec<&str>) = filters
+        .iter()
+        .map(String::as_str)
+        .partition(|filter| filter.starts_with('!'));
+    let selected: Vec<Workspace> = workspaces
+        .iter()
+        .filter(|member| including.is_empty() || including.iter().any(|pattern| matches(member, pattern)))
+        .filter(|member| !excluding.iter().any(|pattern| matches(member, &pattern[1..])))
+        .cloned()
+        .collect();
+    if selected.is_empty() {
+        return Err(WorkspaceError::NoMatch(filters.join(" ")));
+    }
+    Ok(selected)
+}
+
+// Names of the other members `member` depends on, through any kind of
+// dependency
+pub fn workspace_dependencies(workspaces: &[Workspace], member: &Workspace) -> Result<Vec<String>, WorkspaceError> {
+    let manifest = read_manifest(&member.dir)?;
+    let mut names = Vec::new();
+    for field in ["dependencies", "devDependencies", "optionalDependencies", "peerDependencies"] {
+        let Some(dependencies) = manifest[field].as_object() else {
+            continue;
+        };
+        for (name, spec) in dependencies {
+            let linked = find_linked(workspaces, name, spec.as_str().unwrap_or_default())?;
+            if linked.is_some() && *name != member.name && !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+    Ok(names)
+}
+
+fn read_manifest(dir: &Path) -> Result<Value, WorkspaceError> {
+    let path = dir.join("package.json");
+    let contents = fs::read_to_string(&path).map_err(|e| WorkspaceError::ReadFailed(path.clone(), e))?;
+    serde_json::from_str(&contents).map_err(|e| WorkspaceError::ParseFailed(path, e))
+}
+
+fn workspace_patterns(manifest: &Value) -> Vec<String> {
+    let patterns = match &manifest["workspaces"] {
+        Value::Array(patterns) => patterns,
+        Value::Object(fields) => match fields.get("packages") {
+            Some(Value::Array(patterns)) => patterns,
+            _ => return Vec::new(),
+        },
+        _ => return Vec::new(),
+    };
+    patterns
+        .iter()
+        .filter_map(|pattern| pattern.as_str().map(str::to_string))
+        .collect()
+}
+
+// Every directory under `root` a pattern matches
+fn expand_pattern(root: &Path, pattern: &str) -> Vec<PathBuf> {
+    let mut dirs = vec![root.to_path_buf()];
+    for segment in pattern.split('/').filter(|segment| !segment.is_empty() && *segment != ".") {
+        let mut next = Vec::new();
+        for dir in &dirs {
+            if segment == "**" {
+                collect_dirs(dir, &mut next);
+            } else if segment.contains(['*', '?']) {
+                next.extend(subdirs(dir).into_iter().filter(|sub| {
+                    sub.file_name()
+                        .is_some_and(|name| matches_segment(segment, &name.to_string_lossy()))
+                }));
+            } else if segment == ".." {
+                // Members have to be inside the root
+                continue;
+            } else if dir.join(segment).is_dir() {
+                next.push(dir.join(segment));
+            }
+        }
+        dirs = next;
+    }
+    dirs
+}
+
+// The directory and everything below it, skipping node_modules and hidden
+// directories
+fn collect_dirs(dir: &Path, dirs: &mut Vec<PathBuf>) {
+    dirs.push(dir.to_path_buf());
+    for sub in subdirs(dir) {
+        collect_dirs(&sub, dirs);
+    }
+}
+
+fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut dirs: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name != "node_modules" && !name.starts_with('.')
+        })
+        .map(|entry| entry.path())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+// `*` matches any run of characters and `?` a single one
+fn matches_segment(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Positions in the name each prefix of the pattern can end at
+    let mut reachable = vec![false; name.len() + 1];
+    reachable[0] = true;
+    for token in pattern {
+        let mut next = vec![false; name.len() + 1];
+        for position in 0..=name.len() {
+            if !reachable[position] {
+                continue;
+            }
+            match token {
+                '*' => next[position..].iter_mut().for_each(|reached| *reached = true),
+                '?' if position < name.len() => next[position + 1] = true,
+                c if position < name.len() && name[position] == c => next[position + 1] = true,
+                _ => {}
+            }
+        }
+        reachable = next;
+    }
+    reachable[name.len()]
+}
+
+fn relative_key(root: &Path, dir: &Path) -> String {
+    dir.strip_prefix(root)
+        .unwrap_or(dir)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
@@ -0,0 +1,191 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::thread;
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::run::{exit_code, package_label, script_command, script_steps};
+use crate::workspace::{filter_workspaces, find_workspaces, workspace_dependencies, Workspace};
+
+#[derive(Debug, Error)]
+pub enum WorkspaceRunError {
+    #[error("None of the selected workspace members has a \"{0}\" script")]
+    NoScript(String),
+}
+
+struct Member {
+    workspace: Workspace,
+    manifest: Value,
+    // Selected members with the script that have to finish first, directly
+    // or indirectly depended on
+    dependencies: Vec<String>,
+}
+
+// Runs a script in every workspace member the filters select, all of them
+// when there are none, skipping members without it. A member starts once the
+// members it depends on are done, up to `workspace_concurrency` at a time, and
+// every line of output is prefixed with the member's name. No new members
+// start after one fails, and the first non-zero exit code is returned.
+pub fn run_workspaces(
+    root: &Path,
+    current_dir: &Path,
+    script_name: &str,
+    args: &[String],
+    filters: &[String],
+    config: &Config,
+) -> Result<i32, Box<dyn Error + Send + Sync>> {
+    let workspaces = find_workspaces(root)?;
+    let selected = filter_workspaces(root, &workspaces, filters)?;
+
+    let mut members: BTreeMap<String, Member> = BTreeMap::new();
+    for workspace in selected {
+        let manifest: Value = serde_json::from_str(&std::fs::read_to_string(workspace.dir.join("package.json"))?)?;
+        if manifest["scripts"][script_name].as_str().is_some() {
+            members.insert(
+                workspace.name.clone(),
+                Member {
+                    workspace,
+                    manifest,
+                    dependencies: Vec::new(),
+                },
+            );
+        }
+    }
+    if members.is_empty() {
+        return Err(WorkspaceRunError::NoScript(script_name.to_string()).into());
+    }
+    // Members can depend on each other through members that aren't selected
+    // or don't have the script, so the order comes from the whole workspace
+    let mut graph: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for workspace in &workspaces {
+        graph.insert(workspace.name.clone(), workspace_dependencies(&workspaces, workspace)?);
+    }
+    let names: HashSet<String> = members.keys().cloned().collect();
+    for (name, member) in members.iter_mut() {
+        member.dependencies = transitive_dependencies(&graph, name)
+            .into_iter()
+            .filter(|dependency| names.contains(dependency))
+            .collect();
+    }
+
+    let concurrency = config.workspace_concurrency.max(1);
+    let mut pending: Vec<&Member> = members.values().collect();
+    let mut done: HashSet<&str> = HashSet::new();
+    let mut running = 0;
+    let mut failed = 0;
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        loop {
+            while failed == 0 && running < concurrency && !pending.is_empty() {
+                let ready = pending
+                    .iter()
+                    .position(|member| member.dependencies.iter().all(|dependency| done.contains(dependency.as_str())));
+                let position = match ready {
+                    Some(position) => position,
+                    // Members depending on each other in a cycle can't wait for
+                    // one another, one of them goes first
+                    None if running == 0 => {
+                        let names: Vec<&str> = pending.iter().map(|member| member.workspace.name.as_str()).collect();
+                        eprintln!("Workspace members {} depend on each other in a cycle", names.join(", "));
+                        0
+                    }
+                    None => break,
+                };
+                let member = pending.remove(position);
+                let sender = sender.clone();
+                scope.spawn(move || {
+                    let _ = sender.send((member, run_member(member, script_name, args, current_dir, config)));
+                });
+                running += 1;
+            }
+            if running == 0 {
+                break;
+            }
+
+            let Ok((member, result)) = receiver.recv() else {
+                break;
+            };
+            running -= 1;
+            let name = member.workspace.name.as_str();
+            match result {
+                Ok(0) => {
+                    done.insert(name);
+                }
+                Ok(code) => {
+                    eprintln!("{}: {} failed with exit code {}", name, script_name, code);
+                    if failed == 0 {
+                        failed = code;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}: Failed to run {}: {}", name, script_name, e);
+                    if failed == 0 {
+                        failed = 1;
+                    }
+                }
+            }
+        }
+    });
+    Ok(failed)
+}
+
+// Every member `name` depends on, directly or through other members
+fn transitive_dependencies(graph: &BTreeMap<String, Vec<String>>, name: &str) -> BTreeSet<String> {
+    let mut reached = BTreeSet::new();
+    let mut stack: Vec<&String> = graph.get(name).into_iter().flatten().collect();
+    while let Some(dependency) = stack.pop() {
+        if dependency != name && reached.insert(dependency.clone()) {
+            stack.extend(graph.get(dependency).into_iter().flatten());
+        }
+    }
+    reached
+}
+
+// Runs the script of one member along with its `pre` and `post` scripts,
+// stopping at the first that fails
+fn run_member(member: &Member, script_name: &str, args: &[String], init_cwd: &Path, config: &Config) -> std::io::Result<i32> {
+    let name = &member.workspace.name;
+    let label = package_label(&member.manifest, &member.workspace.dir);
+    for (event, script) in script_steps(&member.manifest, script_name, args, config) {
+        println!("{}: > {} {}", name, label, event);
+        println!("{}: > {}", name, script);
+        let command = script_command(&script, &event, &member.manifest, &member.workspace.dir, init_cwd, config);
+        let code = run_prefixed(command, name)?;
+        if code != 0 {
+            return Ok(code);
+        }
+    }
+    Ok(0)
+}
+
+// Runs a script with its output read line by line and printed with `prefix`
+// in front, so the output of members running at once can be told apart
+fn run_prefixed(mut command: std::process::Command, prefix: &str) -> std::io::Result<i32> {
+    let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take().map(|stdout| print_lines(stdout, prefix.to_string(), false));
+    let stderr = child.stderr.take().map(|stderr| print_lines(stderr, prefix.to_string(), true));
+    let status = child.wait()?;
+    for reader in stdout.into_iter().chain(stderr) {
+        let _ = reader.join();
+    }
+    Ok(exit_code(status))
+}
+
+fn print_lines(output: impl Read + Send + 'static, prefix: String, to_stderr: bool) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(output).split(b'\n').map_while(Result::ok) {
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches('\r');
+            if to_stderr {
+                eprintln!("{}: {}", prefix, line);
+            } else {
+                println!("{}: {}", prefix, line);
+            }
+        }
+    })
+}